@@ -0,0 +1,326 @@
+use crate::{
+    lexer::TokenSpan,
+    parser::{Node, OpKind, ProgramTree, StackEffect},
+    runtime::{constant, Builtin, RuntimeError},
+};
+
+// Static checks done before a program starts running:
+//   - every word must be a builtin, a procedure, a definition or a host word;
+//   - calls to procedures with a declared stack effect must find enough elements on the
+//     stack, and the body of such a procedure must leave exactly the declared results.
+// The stack depth is simulated while it can be known. Anything that makes it depend on the
+// program's input (loops, branches that disagree, procedures without a declared effect...)
+// makes it unknown and the related checks are skipped.
+
+type Depth = Option<usize>;
+
+#[derive(Debug)]
+pub enum Warning {
+    ShadowedBuiltin(TokenSpan, String, String), // when a procedure or definition has the name of a reserved builtin
+    OverriddenBuiltin(TokenSpan, String, String), // when it has the name of any other builtin
+    ShadowedCounter(TokenSpan, String, String), // when it has the name of a loop counter
+}
+
+fn apply(depth: Depth, inputs: usize, outputs: usize) -> Depth {
+    depth
+        .and_then(|d| d.checked_sub(inputs))
+        .map(|d| d + outputs)
+}
+
+fn op_effect(op: &OpKind) -> (usize, usize) {
+    match op {
+        OpKind::Swap => (2, 2),
+        OpKind::Over => (2, 3),
+        OpKind::Rot => (3, 3),
+        OpKind::TwoDup => (2, 4),
+        OpKind::TwoSwap => (4, 4),
+        OpKind::TwoDrop => (2, 0),
+        OpKind::Nip => (2, 1),
+        OpKind::Tuck => (2, 3),
+        // how deep they reach depends on the number, only the count itself is known
+        OpKind::Pick => (1, 1),
+        OpKind::Roll => (1, 0),
+        OpKind::Dup => (1, 2),
+        OpKind::Drop | OpKind::Trace => (1, 0),
+        OpKind::BNot => (1, 1),
+        OpKind::Stop => (0, 0),
+        _ => (2, 1),
+    }
+}
+
+// Names of the counters of all the `for` loops in `b`, at any depth.
+fn for_counters<'a>(b: &'a [Node], names: &mut Vec<&'a str>) {
+    for n in b {
+        match n {
+            Node::For(name, l, _) => {
+                if !names.contains(&name.as_str()) {
+                    names.push(name);
+                }
+                for_counters(l, names);
+            }
+            Node::Proc(_, _, l, _) | Node::Def(_, l, _) | Node::Loop(l, _) | Node::Times(l, _) => {
+                for_counters(l, names)
+            }
+            Node::Quote(l, _) => for_counters(l, names),
+            Node::If(a, b, _) => {
+                for_counters(a, names);
+                for_counters(b.as_deref().unwrap_or_default(), names);
+            }
+            Node::Until(a, b, _) | Node::While(a, b, _) | Node::Try(a, b, _) => {
+                for_counters(a, names);
+                for_counters(b, names);
+            }
+            Node::TypeCase(c, e, _) => {
+                c.iter().for_each(|(_, l)| for_counters(l, names));
+                for_counters(e.as_deref().unwrap_or_default(), names);
+            }
+            _ => {}
+        }
+    }
+}
+
+pub struct Checker<'a> {
+    input: &'a ProgramTree,
+    procs: Vec<(&'a str, Option<&'a StackEffect>)>,
+    defs: Vec<&'a str>,
+    hosts: Vec<String>,
+    counters: Vec<&'a str>, // `i` and the names of `for` counters, seen by any procedure
+}
+
+impl<'a> Checker<'a> {
+    pub fn new(input: &'a ProgramTree, hosts: Vec<String>) -> Self {
+        let mut procs = Vec::new();
+        let mut defs = Vec::new();
+        for n in input {
+            match n {
+                Node::Proc(n, e, _, _) => procs.push((n.as_str(), e.as_ref())),
+                Node::Def(n, _, _) => defs.push(n.as_str()),
+                _ => {}
+            }
+        }
+        // counters are looked up while the program runs, so a procedure or quotation called
+        // from a loop sees its counter
+        let mut counters = vec!["i"];
+        for_counters(input, &mut counters);
+        Self {
+            input,
+            procs,
+            defs,
+            hosts,
+            counters,
+        }
+    }
+
+    pub fn check(&self) -> Result<Vec<Warning>, RuntimeError> {
+        let warnings = self.check_names()?;
+
+        // definitions are evaluated before the program, each one should take one element
+        let mut depth = Some(0);
+        for n in self.input {
+            if let Node::Def(_, b, _) = n {
+                if self.block(b, Some(0), &[])? != Some(1) {
+                    depth = None;
+                }
+            }
+        }
+
+        for n in self.input {
+            match n {
+                Node::Proc(name, e, b, s) => {
+                    // a procedure may be called from inside any loop, so all counters are allowed
+                    let end = self.block(b, e.as_ref().map(|e| e.inputs.len()), &self.counters)?;
+                    if let (Some(e), Some(end)) = (e, end) {
+                        if end != e.outputs.len() {
+                            return Err(RuntimeError::StackEffectMismatch(
+                                s.clone(),
+                                name.to_string(),
+                                e.to_string(),
+                                e.outputs.len(),
+                                end as isize,
+                            ));
+                        }
+                    }
+                }
+                Node::Def(..) => {}
+                n => depth = self.node(n, depth, &[])?,
+            }
+        }
+        Ok(warnings)
+    }
+
+    fn check_names(&self) -> Result<Vec<Warning>, RuntimeError> {
+        let mut warnings = Vec::new();
+        let mut names: Vec<&str> = Vec::new();
+        for n in self.input {
+            let (name, kind, span) = match n {
+                Node::Proc(n, _, _, s) => (n, "procedure", s),
+                Node::Def(n, _, s) => (n, "definition", s),
+                _ => continue,
+            };
+            if names.contains(&name.as_str()) || self.hosts.contains(name) {
+                return Err(match n {
+                    Node::Proc(..) => RuntimeError::ProcRedefinition(span.clone(), name.clone()),
+                    _ => RuntimeError::DefRedefinition(span.clone(), name.clone()),
+                });
+            }
+            names.push(name);
+            if let Some(b) = Builtin::from_word(name) {
+                let (span, kind, name) = (span.clone(), kind.to_string(), name.clone());
+                warnings.push(if b.is_reserved() {
+                    Warning::ShadowedBuiltin(span, kind, name)
+                } else {
+                    Warning::OverriddenBuiltin(span, kind, name)
+                });
+            } else if self.counters.contains(&name.as_str()) {
+                warnings.push(Warning::ShadowedCounter(
+                    span.clone(),
+                    kind.to_string(),
+                    name.clone(),
+                ));
+            }
+        }
+        Ok(warnings)
+    }
+
+    fn block(
+        &self,
+        b: &[Node],
+        mut depth: Depth,
+        counters: &[&str],
+    ) -> Result<Depth, RuntimeError> {
+        for n in b {
+            depth = self.node(n, depth, counters)?;
+        }
+        Ok(depth)
+    }
+
+    fn node(&self, n: &Node, depth: Depth, counters: &[&str]) -> Result<Depth, RuntimeError> {
+        Ok(match n {
+            Node::Number(..) | Node::String(..) => depth.map(|d| d + 1),
+            Node::Operation(op, _) => {
+                let (i, o) = op_effect(op);
+                apply(depth, i, o)
+            }
+            Node::Word(w, s) => self.word(w, s, depth, counters)?,
+            Node::If(i, e, _) => {
+                let d = apply(depth, 1, 0);
+                let a = self.block(i, d, counters)?;
+                let b = match e {
+                    Some(e) => self.block(e, d, counters)?,
+                    None => d,
+                };
+                if a == b {
+                    a
+                } else {
+                    None
+                }
+            }
+            Node::TypeCase(c, e, _) => {
+                let d = apply(depth, 1, 1);
+                let mut ends = Vec::new();
+                for (_, b) in c {
+                    ends.push(self.block(b, d, counters)?);
+                }
+                ends.push(match e {
+                    Some(e) => self.block(e, d, counters)?,
+                    None => d,
+                });
+                if ends.iter().all(|x| *x == ends[0]) {
+                    ends[0]
+                } else {
+                    None
+                }
+            }
+            // the catch block starts from the stack as it was before `try`, plus the message
+            Node::Try(t, c, _) => {
+                let a = self.block(t, depth, counters)?;
+                let b = self.block(c, depth.map(|d| d + 1), counters)?;
+                if a == b {
+                    a
+                } else {
+                    None
+                }
+            }
+            // loop bodies are checked as they would run in the first iteration,
+            // the depth after the loop is unknown
+            Node::Loop(b, _) => {
+                self.block(b, depth, counters)?;
+                None
+            }
+            Node::Times(b, _) => {
+                self.block(b, apply(depth, 1, 0), &[counters, &["i"]].concat())?;
+                None
+            }
+            Node::For(name, b, _) => {
+                self.block(
+                    b,
+                    apply(depth, 1, 0),
+                    &[counters, &[name.as_str()]].concat(),
+                )?;
+                None
+            }
+            Node::Until(c, b, _) => {
+                let d = self.block(b, depth, counters)?;
+                self.block(c, d, counters)?;
+                None
+            }
+            Node::While(c, b, _) => {
+                let d = self.block(c, depth, counters)?;
+                self.block(b, apply(d, 1, 0), counters)?;
+                None
+            }
+            // like a procedure body, a quotation can be called from anywhere, so all the
+            // counters are allowed
+            Node::Quote(b, _) => {
+                self.block(b, None, &self.counters)?;
+                depth.map(|d| d + 1)
+            }
+            Node::Proc(..) | Node::Def(..) | Node::Debug(..) => depth,
+        })
+    }
+
+    fn word(
+        &self,
+        w: &str,
+        s: &TokenSpan,
+        depth: Depth,
+        counters: &[&str],
+    ) -> Result<Depth, RuntimeError> {
+        // the same order in which the runtime looks words up
+        let builtin = Builtin::from_word(w);
+        if let Some(b) = builtin.as_ref().filter(|b| b.is_reserved()) {
+            return Ok(b.effect().and_then(|(i, o)| apply(depth, i, o)));
+        }
+        if counters.contains(&w) {
+            return Ok(depth.map(|d| d + 1));
+        }
+        if self.hosts.iter().any(|h| h == w) {
+            return Ok(None);
+        }
+        if let Some((_, e)) = self.procs.iter().find(|p| p.0 == w) {
+            return match e {
+                Some(e) => {
+                    if depth.is_some_and(|d| d < e.inputs.len()) {
+                        return Err(RuntimeError::StackUnderflow(
+                            s.clone(),
+                            w.to_string(),
+                            e.inputs.len(),
+                        ));
+                    }
+                    Ok(apply(depth, e.inputs.len(), e.outputs.len()))
+                }
+                None => Ok(None),
+            };
+        }
+        if self.defs.contains(&w) {
+            return Ok(depth.map(|d| d + 1));
+        }
+        if let Some(b) = builtin {
+            return Ok(b.effect().and_then(|(i, o)| apply(depth, i, o)));
+        }
+        if constant(w).is_some() {
+            return Ok(depth.map(|d| d + 1));
+        }
+        Err(RuntimeError::InvalidWord(s.clone(), w.to_string()))
+    }
+}
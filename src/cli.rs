@@ -1,78 +1,214 @@
 use rustc_version::version_meta;
 use std::env::args;
+use std::io::IsTerminal;
+use std::str::FromStr;
 
 pub enum CLIError {
     InvalidFlag(String),
     ExpectedArgument(String),
     UnexpectedArgument(String),
+    InvalidValue(String, String),
 }
 
+#[derive(Default)]
 pub struct Arguments {
     pub filename: String,
+    pub preload: Vec<String>, // files given before FILENAME, run before it in order
     pub show_help: bool,
     pub show_version: bool,
-}
-
-impl Arguments {
-    fn new(filename: String, show_help: bool, show_version: bool) -> Self {
-        Self {
-            filename,
-            show_help,
-            show_version,
-        }
-    }
+    pub timeout: Option<f64>,
+    pub new_project: Option<String>, // `pile new NAME`
+    pub from_manifest: bool,         // `pile run` without a file: run the project's entry point
+    pub build: bool,                 // `pile build FILENAME`: make a standalone executable
+    pub format: bool,                // `pile fmt FILENAME`: rewrite the file formatted
+    pub output: Option<String>,
+    pub no_debug: bool,
+    pub entry: Option<String>, // procedure to call after the top-level code
+    pub config: Option<String>, // configuration file to use instead of the default one
+    pub args: Vec<String>,     // everything after `--`, given to the program
+    pub max_call_depth: Option<usize>,
+    pub max_instructions: Option<usize>,
+    pub max_memory: Option<usize>, // in bytes
+    pub eval: Option<String>,      // `-e CODE`: run CODE instead of a file
+    pub check: bool,               // only parse and check the program, don't run it
+    pub dump_tokens: bool,         // print the tokens of the program instead of running it
+    pub trace: bool,               // print every step of the program as it runs
 }
 
 pub fn show_usage() {
-    eprintln!("pile: usage: pile FILENAME [-h] [-v]");
+    eprintln!("pile: usage: pile [LIBRARY...] FILENAME [FLAGS...] [-- ARGS...]");
+    eprintln!("             pile [LIBRARY...] -e CODE [FLAGS...] [-- ARGS...]");
+    eprintln!("             pile run [[LIBRARY...] FILENAME] [FLAGS...] [-- ARGS...]");
+    eprintln!("             pile new NAME");
+    eprintln!("             pile build FILENAME [-o OUTPUT]");
+    eprintln!("             pile fmt FILENAME");
 }
 
 pub fn show_help() {
     println!("pile help:");
     println!("  positional arguments:");
-    println!("    FILENAME         File path of Pile code");
+    println!("    FILENAME               File path of Pile code, or - to read it from stdin");
+    println!("    LIBRARY                Files run before FILENAME, so it can use their procs");
+    println!("    ARGS                   Arguments for the program, read with `argc` and `argv`");
+    println!("  commands:");
+    println!("    run [FILENAME]         Run FILENAME, or the entry point in pile.toml");
+    println!("    new NAME               Create a project in the directory NAME");
+    println!("    build FILENAME         Make a standalone executable that runs FILENAME");
+    println!("    fmt FILENAME           Rewrite FILENAME with the standard indentation");
     println!("  flags:");
-    println!("    -h, --help       Show this help message and exit");
-    println!("    -v, --version    Show the version information and exit");
+    println!("    -h, --help             Show this help message and exit");
+    println!("    -v, --version          Show the version information and exit");
+    println!("    -e, --eval CODE        Run CODE instead of a file");
+    println!("    --check                Check the program for errors without running it");
+    println!("    --dump-tokens          Print the tokens of the program and exit");
+    println!("    --trace                Print every step as it runs, with the top of the stack");
+    println!("    --timeout SECONDS      Stop the program if it runs for more than SECONDS");
+    println!("    -o, --output OUTPUT    Where `pile build` writes the executable");
+    println!("    --max-call-depth DEPTH How deep procedure calls can be nested (default 1000)");
+    println!("    --max-instructions N   Stop the program after N steps");
+    println!(
+        "    --max-memory SIZE      Stop the program if it takes more than SIZE bytes (e.g. 64M)"
+    );
+    println!("    --no-debug             Make `debug` do nothing");
+    println!("    --entry PROC           Call the procedure PROC after the top-level code");
+    println!("    --config FILE          Read the configuration from FILE");
 }
 
 fn rustc_version() -> String {
     let v = version_meta().unwrap();
-    return format!("{} {}", v.short_version_string, v.host);
+    format!("{} {}", v.short_version_string, v.host)
 }
 
 pub fn show_version(v: &str) {
     println!("pile programming language {}\n{}", v, rustc_version());
 }
 
+// parses the value that follows a flag (e.g. `--timeout 10`)
+fn flag_value<T: FromStr>(flag: &str, value: Option<String>, name: &str) -> Result<T, CLIError> {
+    let v = value.ok_or_else(|| CLIError::ExpectedArgument(format!("{name} (after {flag})")))?;
+    v.parse()
+        .map_err(|_| CLIError::InvalidValue(flag.to_string(), v))
+}
+
+// a number of bytes, optionally followed by K, M or G (powers of 1024)
+pub fn parse_size(v: &str) -> Option<usize> {
+    let (n, unit) = match v.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => v.split_at(i),
+        None => (v, ""),
+    };
+    let unit: usize = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return None,
+    };
+    n.parse::<usize>().ok()?.checked_mul(unit)
+}
+
 pub fn parse_arguments() -> Result<Arguments, CLIError> {
-    let args = args().skip(1);
-    let mut filename = None;
-    let mut show_help = false;
-    let mut show_version = false;
+    let mut args = args().skip(1);
+    let mut a = Arguments::default();
+    let mut files = Vec::new();
+    let mut command = None;
 
-    for arg in args.into_iter() {
+    while let Some(arg) = args.next() {
         match arg.as_str() {
+            "--" => {
+                a.args = args.by_ref().collect();
+                break;
+            }
+            "-" => files.push(arg), // the program is read from stdin
             flag if arg.starts_with("-") => match flag {
-                "-h" | "--help" => show_help = true,
-                "-v" | "--version" => show_version = true,
+                "-h" | "--help" => a.show_help = true,
+                "-v" | "--version" => a.show_version = true,
+                "--timeout" => {
+                    let t: f64 = flag_value(flag, args.next(), "SECONDS")?;
+                    // `inf` and huge numbers don't fit in a `Duration`
+                    if t <= 0.0 || std::time::Duration::try_from_secs_f64(t).is_err() {
+                        return Err(CLIError::InvalidValue(flag.to_string(), t.to_string()));
+                    }
+                    a.timeout = Some(t);
+                }
+                "--max-call-depth" => {
+                    let n: usize = flag_value(flag, args.next(), "DEPTH")?;
+                    if n == 0 {
+                        return Err(CLIError::InvalidValue(flag.to_string(), n.to_string()));
+                    }
+                    a.max_call_depth = Some(n);
+                }
+                "-e" | "--eval" => a.eval = Some(flag_value(flag, args.next(), "CODE")?),
+                "--check" => a.check = true,
+                "--dump-tokens" => a.dump_tokens = true,
+                "--trace" => a.trace = true,
+                "--max-instructions" => {
+                    let n: usize = flag_value(flag, args.next(), "N")?;
+                    if n == 0 {
+                        return Err(CLIError::InvalidValue(flag.to_string(), n.to_string()));
+                    }
+                    a.max_instructions = Some(n);
+                }
+                "--max-memory" => {
+                    let v: String = flag_value(flag, args.next(), "SIZE")?;
+                    match parse_size(&v) {
+                        Some(n) if n > 0 => a.max_memory = Some(n),
+                        _ => return Err(CLIError::InvalidValue(flag.to_string(), v)),
+                    }
+                }
+                "--no-debug" => a.no_debug = true,
+                "--entry" => a.entry = Some(flag_value(flag, args.next(), "PROC")?),
+                "--config" => a.config = Some(flag_value(flag, args.next(), "FILE")?),
+                "-o" | "--output" => a.output = Some(flag_value(flag, args.next(), "OUTPUT")?),
                 _ => return Err(CLIError::InvalidFlag(flag.to_string())),
             },
-            _ => {
-                if let Some(_) = filename {
-                    return Err(CLIError::UnexpectedArgument(arg));
-                }
-                filename = Some(arg);
+            "run" | "new" | "build" | "fmt" if command.is_none() && files.is_empty() => {
+                command = Some(arg)
             }
+            _ => files.push(arg),
+        }
+    }
+
+    // with `-e` every file is a library and the code is the program
+    if a.eval.is_some() {
+        if let Some(c) = command {
+            return Err(CLIError::UnexpectedArgument(c));
         }
+        a.preload = files;
+        a.filename = "<eval>".to_string();
+        return Ok(a);
     }
 
-    if let Some(f) = filename {
-        Ok(Arguments::new(f, show_help, show_version))
-    } else {
-        if show_help || show_version {
-            return Ok(Arguments::new("".to_string(), show_help, show_version));
+    // `pile lib1.pile lib2.pile main.pile`: the last file is the program
+    let filename = files.pop();
+    a.preload = files;
+    if matches!(command.as_deref(), Some("new" | "build" | "fmt")) && !a.preload.is_empty() {
+        return Err(CLIError::UnexpectedArgument(filename.unwrap()));
+    }
+
+    match (command.as_deref(), filename) {
+        (Some("new"), Some(name)) => a.new_project = Some(name),
+        (Some("new"), None) if !a.show_help => {
+            return Err(CLIError::ExpectedArgument("NAME".to_string()))
+        }
+        (Some("build"), Some(f)) => {
+            a.build = true;
+            a.filename = f;
+        }
+        (Some("fmt"), Some(f)) => {
+            a.format = true;
+            a.filename = f;
+        }
+        (Some("build" | "fmt"), None) if !a.show_help => {
+            return Err(CLIError::ExpectedArgument("FILENAME".to_string()))
+        }
+        (Some(_), None) => a.from_manifest = true,
+        (_, Some(f)) => a.filename = f,
+        // `echo '1 println' | pile` reads the program from stdin
+        (None, None) if !std::io::stdin().is_terminal() => a.filename = "-".to_string(),
+        (None, None) if !(a.show_help || a.show_version) => {
+            return Err(CLIError::ExpectedArgument("FILENAME".to_string()))
         }
-        Err(CLIError::ExpectedArgument("FILENAME".to_string()))
+        _ => {}
     }
+    Ok(a)
 }
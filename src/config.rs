@@ -0,0 +1,104 @@
+// The user configuration: ~/.config/pile/config.toml (or the file given with --config).
+// Flags given on the command line take precedence over it.
+//
+//     [run]
+//     timeout = 10         # seconds, like --timeout
+//     max-call-depth = 1000
+//     max-instructions = 1000000
+//     max-memory = "64M"   # bytes, or a string with K, M or G like --max-memory
+//
+//     [output]
+//     colors = "auto"      # "auto", "always" or "never" (for `stylize` and friends)
+//     warnings = true      # show the warnings found before running
+use crate::cli::parse_size;
+use crate::toml::{self, Value};
+use std::path::{Path, PathBuf};
+
+#[derive(Default)]
+pub struct Config {
+    pub timeout: Option<f64>,
+    pub max_call_depth: Option<usize>,
+    pub max_instructions: Option<usize>,
+    pub max_memory: Option<usize>, // in bytes
+    pub colors: Option<bool>,      // None = only when writing to a terminal
+    pub warnings: Option<bool>,
+}
+
+fn default_path() -> Option<PathBuf> {
+    let env = |k: &str| {
+        std::env::var_os(k)
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+    };
+    let dir = if cfg!(windows) {
+        env("APPDATA")?
+    } else {
+        env("XDG_CONFIG_HOME").or_else(|| env("HOME").map(|h| h.join(".config")))?
+    };
+    Some(dir.join("pile").join("config.toml"))
+}
+
+// Loads the given configuration file, or the default one if it exists.
+pub fn load(path: Option<&str>) -> Result<Config, String> {
+    let path = match path {
+        Some(p) => PathBuf::from(p),
+        None => match default_path() {
+            Some(p) if p.is_file() => p,
+            _ => return Ok(Config::default()),
+        },
+    };
+    read(&path)
+}
+
+fn is_count(n: f64) -> bool {
+    n >= 1.0 && n.fract() == 0.0 && n <= usize::MAX as f64
+}
+
+// `inf` and numbers too large for a `Duration` are refused like with --timeout
+fn is_timeout(t: f64) -> bool {
+    t > 0.0 && std::time::Duration::try_from_secs_f64(t).is_ok()
+}
+
+fn read(path: &Path) -> Result<Config, String> {
+    let mut c = Config::default();
+    for e in toml::read(path)? {
+        let error = |m: &str| format!("{}:{}: `{}` {}.", path.display(), e.line, e.key, m);
+        match (e.section.as_str(), e.key.as_str(), &e.value) {
+            ("run", "timeout", Value::Number(t)) if is_timeout(*t) => c.timeout = Some(*t),
+            ("run", "timeout", _) => {
+                return Err(error(
+                    "must be a positive number of seconds, and not too large",
+                ))
+            }
+            ("run", "max-call-depth", Value::Number(n)) if is_count(*n) => {
+                c.max_call_depth = Some(*n as usize)
+            }
+            ("run", "max-instructions", Value::Number(n)) if is_count(*n) => {
+                c.max_instructions = Some(*n as usize)
+            }
+            ("run", "max-call-depth" | "max-instructions", _) => {
+                return Err(error("must be a positive whole number"))
+            }
+            ("run", "max-memory", Value::Number(n)) if is_count(*n) => {
+                c.max_memory = Some(*n as usize)
+            }
+            ("run", "max-memory", Value::String(s)) if parse_size(s).is_some_and(|n| n > 0) => {
+                c.max_memory = parse_size(s)
+            }
+            ("run", "max-memory", _) => {
+                return Err(error("must be a number of bytes or a size like \"64M\""))
+            }
+            ("output", "colors", Value::String(s)) if s == "auto" => c.colors = None,
+            ("output", "colors", Value::String(s)) if s == "always" => c.colors = Some(true),
+            ("output", "colors", Value::String(s)) if s == "never" => c.colors = Some(false),
+            ("output", "colors", _) => {
+                return Err(error("must be \"auto\", \"always\" or \"never\""))
+            }
+            ("output", "warnings", Value::Bool(b)) => c.warnings = Some(*b),
+            ("output", "warnings", _) => return Err(error("must be true or false")),
+            // unknown keys are left for newer versions of pile
+            _ => {}
+        }
+    }
+    Ok(c)
+}
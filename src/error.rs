@@ -1,89 +1,123 @@
 use crate::{
-    cli::{show_help, show_usage},
+    checker::Warning,
+    cli::{show_help, show_usage, CLIError},
     lexer::TokenSpan,
     parser::ParseError,
     runtime::RuntimeError,
-    CLIError,
 };
 
-fn match_runtime_error(e: &RuntimeError, call: Option<TokenSpan>) {
+// What kind of error `e` is, its message and a hint to fix it.
+fn explain(e: &RuntimeError) -> (&'static str, String, Option<String>) {
     match e {
-        RuntimeError::ProcedureError { .. } => unreachable!(),
-        RuntimeError::InvalidWord(span, x) => {
-            throw(
-                "runtime error",
-                &format!("`{x}` is not defined."),
-                span.clone(),
-                Some("maybe a typo?"),
-                call,
-            );
-        }
-        RuntimeError::EmptyDefinition(span, x) => {
-            throw(
-                "runtime error",
-                &format!("definition `{x}` has no value to be associated with. `x` can't be bound to anything."),
-                span.clone(),
-                Some("add values to the definition body."),
-                call,
-            );
-        }
-        RuntimeError::StackUnderflow(span, n, x) => {
-            throw(
-                "runtime error",
-                &format!("operation `{n}` expects {x} element(s) on top of the stack but got a different amount."),
-                span.clone(),
-                Some("try checking the values before the operation."),
-                call,
-            );
-        }
-        RuntimeError::UnexpectedType(span, n, x, y) => {
-            throw(
-                "runtime error",
-                &format!(
-                    "operation `{n}` expects {x} datatype(s) on the stack to work, but got {y}."
-                ),
-                span.clone(),
-                Some("try checking the values before the operation."),
-                call,
-            );
-        }
-        RuntimeError::ProcRedefinition(span, x) => {
-            throw(
+        RuntimeError::ProcedureError { inner, .. } => explain(inner),
+        RuntimeError::InvalidWord(_, x) => (
+            "runtime error",
+            format!("`{x}` is not defined."),
+            Some("maybe a typo?".to_string()),
+        ),
+        RuntimeError::EmptyDefinition(_, x) => (
+            "runtime error",
+            format!("definition `{x}` has no value to be associated with. `x` can't be bound to anything."),
+            Some("add values to the definition body.".to_string()),
+        ),
+        RuntimeError::StackUnderflow(_, n, x) => (
+            "runtime error",
+            format!("operation `{n}` expects {x} element(s) on top of the stack but got a different amount."),
+            Some("try checking the values before the operation.".to_string()),
+        ),
+        RuntimeError::UnexpectedType(_, n, x, y) => (
+            "runtime error",
+            format!("operation `{n}` expects {x} datatype(s) on the stack to work, but got {y}."),
+            Some("try checking the values before the operation.".to_string()),
+        ),
+        RuntimeError::ProcRedefinition(_, x) => (
+            "runtime error",
+            format!("tried to redefine the procedure `{x}` (this name is already taken)."),
+            None,
+        ),
+        RuntimeError::DefRedefinition(_, x) => (
+            "runtime error",
+            format!("tried to redefine the definition `{x}` (this name is already taken)."),
+            None,
+        ),
+        RuntimeError::ValueError(_, n, x, y) => (
+            "runtime error",
+            format!("value error: operation `{n}` expected valid literal value for {x}, but got {y}."),
+            Some(format!("likely caused by an invalid conversion to a {x}.")),
+        ),
+        RuntimeError::StackEffectMismatch(_, n, e, x, y) => {
+            let got = if *y < 0 {
+                format!("consumed {} more element(s) than declared", -y)
+            } else {
+                format!("left {y} element(s)")
+            };
+            (
                 "runtime error",
-                &format!("tried to redefine the procedure `{x}` (this name is already taken)."),
-                span.clone(),
-                None,
-                call,
-            );
+                format!("procedure `{n}` is declared as `{e}` and should leave {x} element(s) on the stack, but it {got}."),
+                Some(format!("check the body of `{n}` or fix its stack effect.")),
+            )
         }
-        RuntimeError::DefRedefinition(span, x) => {
-            throw(
-                "runtime error",
-                &format!("tried to redefine the definition `{x}` (this name is already taken)."),
-                span.clone(),
+        RuntimeError::CallStackOverflow(_, x) => (
+            "runtime error",
+            format!("call stack overflow: procedures and quotations were nested more than {x} levels deep, or the blocks inside of them too deeply."),
+            Some("maybe a recursion that never ends? the limit can be raised with --max-call-depth.".to_string()),
+        ),
+        RuntimeError::InstructionLimit(_, x) => (
+            "runtime error",
+            format!("instruction limit reached: the program was still running after {x} step(s). this is where it was when it got stopped."),
+            Some("maybe a loop that never stops? the limit is set with --max-instructions.".to_string()),
+        ),
+        RuntimeError::MemoryLimit(_, x) => (
+            "runtime error",
+            format!("memory limit reached: the program took more than {x} byte(s) of memory. this is where it was when it got stopped."),
+            Some("maybe a loop that keeps pushing values or growing a string? the limit is set with --max-memory.".to_string()),
+        ),
+        RuntimeError::Timeout(_, x) => (
+            "runtime error",
+            format!("timed out: the program was still running after {x} second(s). this is where it was when it got interrupted."),
+            Some("maybe a loop that never stops?".to_string()),
+        ),
+        RuntimeError::Interrupted(_, x) => {
+            let top = match x {
+                Some(x) => format!("the top of the stack was {x}."),
+                None => "the stack was empty.".to_string(),
+            };
+            (
+                "interrupted",
+                format!("the program was interrupted (Ctrl+C) here. {top}"),
                 None,
-                call,
-            );
-        }
-        RuntimeError::ValueError(span, n, x, y) => {
-            throw(
-                "runtime error",
-                &format!("value error: operation `{n}` expected valid literal value for {x}, but got {y}."),
-                span.clone(),
-                Some(&format!("likely caused by an invalid conversion to a {x}.")),
-                call,
-            );
+            )
         }
+        RuntimeError::HostError(_, n, x) => (
+            "runtime error",
+            format!("host word `{n}` failed: {x}"),
+            None,
+        ),
+        RuntimeError::IOError(_, op, x) => (
+            "runtime error",
+            format!("operation `{op}` failed: {x}"),
+            Some("check that the path or stream exists and can be accessed.".to_string()),
+        ),
     }
 }
 
+// The message of `e` without its location, as `catch` blocks receive it.
+pub fn error_message(e: &RuntimeError) -> String {
+    explain(e).1
+}
+
 pub fn runtime_error(e: RuntimeError) {
-    match e {
-        RuntimeError::ProcedureError { call: c, inner: i } => {
-            match_runtime_error(i.as_ref(), Some(c));
-        }
-        x => match_runtime_error(&x, None),
+    // errors inside procedures are wrapped once per procedure call,
+    // the innermost call is reported first
+    let mut calls = Vec::new();
+    let mut e = &e;
+    while let RuntimeError::ProcedureError { call: c, inner: i } = e {
+        calls.push(c.clone());
+        e = i.as_ref();
     }
+    calls.reverse();
+    let (error, message, help) = explain(e);
+    throw(error, &message, e.span().clone(), help.as_deref(), &calls);
 }
 
 pub fn parse_error(e: ParseError) {
@@ -91,10 +125,10 @@ pub fn parse_error(e: ParseError) {
         ParseError::UnmatchedBlock(span) => {
             throw(
                 "parse error",
-                "syntax error: found unmatched block: termination of block (end) provided without a beginning (`if`, `else`, `proc`, `def`, or `loop`)",
+                "syntax error: found unmatched block: termination of block (end) provided without a beginning (`if`, `else`, `proc`, `def`, `loop`, `times`, `for`, `until`, `while`, `typecase`, `try` or `quote`)",
                 span,
                 None,
-                None,
+                &[],
             );
         }
         ParseError::UnterminatedBlock(span, x) => {
@@ -103,7 +137,7 @@ pub fn parse_error(e: ParseError) {
                 &format!("syntax error: found unterminated block: termination of block not provided from `{x}` block"),
                 span,
                 Some("perhaps you forgot to write `end`?"),
-                None,
+                &[],
             );
         }
         ParseError::UnexpectedEOF(span, x) => {
@@ -114,7 +148,16 @@ pub fn parse_error(e: ParseError) {
                 ),
                 span,
                 None,
-                None,
+                &[],
+            );
+        }
+        ParseError::StopOutsideLoop(span) => {
+            throw(
+                "parse error",
+                "syntax error: `stop` used outside of a loop (`loop`, `times`, `for`, `until` or `while`)",
+                span,
+                Some("there is no loop here for `stop` to end."),
+                &[],
             );
         }
         ParseError::UnexpectedToken(span, x, y) => {
@@ -123,7 +166,7 @@ pub fn parse_error(e: ParseError) {
                 &format!("syntax error: unexpected token while parsing: expected {y} but got {x}"),
                 span,
                 None,
-                None,
+                &[],
             );
         }
     };
@@ -142,6 +185,9 @@ pub fn cli_error(e: CLIError) {
         CLIError::UnexpectedArgument(x) => {
             fatal(&format!("found unexpected argument: {x}"));
         }
+        CLIError::InvalidValue(x, y) => {
+            fatal(&format!("invalid value for {x}: {y}"));
+        }
     }
 }
 
@@ -150,23 +196,72 @@ pub fn fatal(message: &str) {
     std::process::exit(1);
 }
 
-pub fn throw(
+pub fn warning(w: Warning) {
+    match w {
+        Warning::ShadowedBuiltin(span, k, x) => {
+            report(
+                "warning",
+                "warning",
+                &format!("{k} `{x}` has the same name as a builtin. the builtin always takes precedence, so `{x}` can never be used."),
+                span,
+                Some("choose another name."),
+                &[],
+            );
+        }
+        Warning::OverriddenBuiltin(span, k, x) => {
+            report(
+                "warning",
+                "warning",
+                &format!("{k} `{x}` has the same name as a builtin. the {k} takes precedence, so the builtin `{x}` can't be used in this program."),
+                span,
+                Some("choose another name if you need the builtin."),
+                &[],
+            );
+        }
+        Warning::ShadowedCounter(span, k, x) => {
+            report(
+                "warning",
+                "warning",
+                &format!("{k} `{x}` has the same name as the counter of {}, so it can't be used inside of them.", if x == "i" { "`times` loops".to_string() } else { format!("`for {x}` loops") }),
+                span,
+                Some("choose another name."),
+                &[],
+            );
+        }
+    }
+}
+
+pub fn throw(error: &str, message: &str, span: TokenSpan, help: Option<&str>, calls: &[TokenSpan]) {
+    report("error", error, message, span, help, calls);
+    std::process::exit(1);
+}
+
+fn report(
+    level: &str,
     error: &str,
     message: &str,
     span: TokenSpan,
     help: Option<&str>,
-    call: Option<TokenSpan>,
+    calls: &[TokenSpan],
 ) {
     eprintln!(
-        "pile: error at {}:{}:{}:",
+        "pile: {level} at {}:{}:{}:",
         span.filename, span.line, span.col
     );
-    if let Some(c) = call {
+    // deep recursion would print thousands of calls, only both ends are shown
+    for (i, c) in calls.iter().enumerate() {
+        if calls.len() > 10 && (5..calls.len() - 5).contains(&i) {
+            if i == 5 {
+                eprintln!("    > ... {} more procedure calls", calls.len() - 10);
+            }
+            continue;
+        }
         eprintln!(
             "    > from procedure call at {}:{}:{}:",
             c.filename, c.line, c.col
         );
     }
+    show_source(&span);
     eprintln!("    |    {error}:");
     for line in break_line_at(message.to_string(), 50) {
         eprintln!("    |        {line}");
@@ -176,7 +271,70 @@ pub fn throw(
             eprintln!("    +    {line}");
         }
     }
-    std::process::exit(1);
+}
+
+// Prints the line of `span` with the token at its column underlined.
+// Nothing is printed if the file can't be read anymore, or if the span doesn't come from
+// a file that can be read (`--entry`, calls from the host and programs read from stdin).
+fn show_source(span: &TokenSpan) {
+    let pseudo = matches!(&*span.filename, "--entry" | "host" | "<stdin>");
+    if pseudo || span.line == 0 {
+        return;
+    }
+    let Ok(source) = std::fs::read_to_string(&*span.filename) else {
+        return;
+    };
+    let Some(line) = source.lines().nth(span.line - 1) else {
+        return;
+    };
+    let width = std::env::var("COLUMNS")
+        .ok()
+        .and_then(|c| c.parse::<usize>().ok())
+        .unwrap_or(80)
+        .saturating_sub(12)
+        .max(20);
+    let (shown, underline) = snippet(line, span.col, width);
+    eprintln!("    |");
+    eprintln!("{:>4}|    {}", span.line, shown);
+    eprintln!("    |    {underline}");
+}
+
+// The part of `line` that fits in `width` columns around the token at `col`, and the
+// line that underlines that token.
+fn snippet(line: &str, col: usize, width: usize) -> (String, String) {
+    let line: Vec<char> = line.chars().collect();
+    let start = col.saturating_sub(1).min(line.len());
+    // spans only know where a token starts, so its end is found again here
+    let rest = &line[start..];
+    let len = match rest.first() {
+        Some('"') => rest[1..]
+            .iter()
+            .position(|&c| c == '"')
+            .map_or(rest.len(), |i| i + 2),
+        _ => rest
+            .iter()
+            .position(|c| c.is_whitespace())
+            .unwrap_or(rest.len()),
+    }
+    .max(1);
+    // long lines are cut around the token so that they fit in the terminal, but never
+    // past its start: a token longer than the width is shown from its beginning
+    let first = if line.len() > width && start + len > width {
+        (start + len)
+            .saturating_sub(width / 2)
+            .min(line.len() - width)
+            .min(start)
+    } else {
+        0
+    };
+    let shown: String = line[first..line.len().min(first + width)].iter().collect();
+    // tabs are kept in the padding so the carets line up with the token
+    let padding: String = line[first..start]
+        .iter()
+        .map(|&c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let carets = "^".repeat(len.min(width.saturating_sub(start - first)).max(1));
+    (shown.trim_end().to_string(), padding + &carets)
 }
 
 fn break_line_at(value: String, n: usize) -> Vec<String> {
@@ -190,8 +348,51 @@ fn break_line_at(value: String, n: usize) -> Vec<String> {
             line = String::new();
         }
     }
-    if line.len() > 0 {
+    if !line.is_empty() {
         lines.push(line.clone());
     }
     lines
 }
+
+#[cfg(test)]
+mod tests {
+    use super::snippet;
+
+    #[test]
+    fn short_lines_are_shown_whole() {
+        let (shown, underline) = snippet("1 2 foo +", 5, 40);
+        assert_eq!(shown, "1 2 foo +");
+        assert_eq!(underline, "    ^^^");
+    }
+
+    #[test]
+    fn long_lines_are_cut_around_the_token() {
+        let line = format!("{}foo {}", "1 ".repeat(60), "2 ".repeat(60));
+        let (shown, underline) = snippet(&line, 121, 40);
+        assert!(shown.chars().count() <= 40);
+        let col = underline.find('^').unwrap();
+        assert_eq!(&shown[col..col + 3], "foo");
+        assert_eq!(&underline[col..], "^^^");
+    }
+
+    #[test]
+    fn tokens_longer_than_the_line_are_shown_from_their_start() {
+        let line = format!("1 2 {} +", "x".repeat(100));
+        let (shown, underline) = snippet(&line, 5, 40);
+        assert_eq!(shown, "x".repeat(40));
+        assert_eq!(underline, "^".repeat(40));
+    }
+
+    #[test]
+    fn a_long_token_in_the_middle_doesnt_panic() {
+        let line = format!(
+            "{}\"{}\" {}",
+            "1 ".repeat(30),
+            "a".repeat(80),
+            "2 ".repeat(30)
+        );
+        let (shown, underline) = snippet(&line, 61, 40);
+        assert!(shown.starts_with('"'));
+        assert_eq!(underline, "^".repeat(40));
+    }
+}
@@ -0,0 +1,105 @@
+// `pile fmt`: re-emits a file with one space between tokens and the contents of every
+// block indented by four spaces. Line breaks (and one blank line between groups) are kept
+// where the author put them, comments included.
+use crate::lexer::{InputFile, Lexer, Span, Token, TokenKind};
+
+const INDENT: &str = "    ";
+
+fn opens_block(value: &str) -> bool {
+    matches!(
+        value,
+        "proc"
+            | "def"
+            | "if"
+            | "loop"
+            | "times"
+            | "for"
+            | "until"
+            | "while"
+            | "typecase"
+            | "quote"
+            | "try"
+    )
+}
+
+// words that go back to the indentation of the block they belong to when they start a line
+fn continues_block(value: &str) -> bool {
+    matches!(value, "end" | "else" | "elif" | "do" | "case" | "catch")
+}
+
+// the lexer resolves escape sequences, so they're written back here
+fn escape(value: &str) -> String {
+    let mut out = String::new();
+    for c in value.chars() {
+        match c {
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '\0' => out.push_str("\\0"),
+            '\x1b' => out.push_str("\\e"),
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            _ if c.is_ascii_control() => out.push_str(&format!("\\x{:02X}", c as u8)),
+            _ if c.is_control() => out.push_str(&format!("\\u{{{:X}}}", c as u32)),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn show(t: &Token) -> String {
+    match t.kind {
+        TokenKind::String => format!("\"{}\"", escape(&t.value)),
+        _ => t.value.clone(),
+    }
+}
+
+pub fn format(filename: &str, source: &str) -> String {
+    let f = InputFile {
+        name: filename,
+        content: source.chars().peekable(),
+    };
+    let mut l = Lexer::new(f, Span { line: 1, col: 1 });
+    l.keep_comments();
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut depth: usize = 0;
+    let mut last_line = None;
+    let mut after_case = false; // the type name after `case` isn't a block, even if it's `quote`
+
+    while let Some(t) = l.next() {
+        let keyword = t.kind == TokenKind::Word && !after_case;
+        match last_line {
+            Some(n) if n == t.span.line => {
+                let line = lines.last_mut().unwrap();
+                line.push(' ');
+                line.push_str(&show(&t));
+            }
+            _ => {
+                if last_line.is_some_and(|n| t.span.line > n + 1) {
+                    lines.push(String::new());
+                }
+                let level = if keyword && continues_block(&t.value) {
+                    depth.saturating_sub(1)
+                } else {
+                    depth
+                };
+                lines.push(INDENT.repeat(level) + &show(&t));
+            }
+        }
+        last_line = Some(l.line());
+        after_case = keyword && t.value == "case";
+
+        if keyword && opens_block(&t.value) {
+            depth += 1;
+        } else if keyword && t.value == "end" {
+            depth = depth.saturating_sub(1);
+        }
+    }
+
+    let mut out = lines.join("\n");
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    out
+}
@@ -1,5 +1,6 @@
 use crate::error::*;
 use std::iter::{Iterator, Peekable};
+use std::rc::Rc;
 use std::str::Chars;
 
 #[derive(Debug, PartialEq)]
@@ -7,6 +8,7 @@ pub enum TokenKind {
     Word,
     Number,
     String,
+    Comment, // only produced when the lexer keeps comments (see `Lexer::keep_comments`)
 }
 
 #[derive(Debug)]
@@ -30,12 +32,12 @@ pub struct Span {
 
 #[derive(Debug, Clone)]
 pub struct TokenSpan {
-    pub filename: String,
+    pub filename: Rc<str>, // shared by all the spans of a file, so cloning a span is cheap
     pub line: usize,
     pub col: usize,
 }
 
-impl<'a> Token {
+impl Token {
     pub fn new(value: String, kind: TokenKind, span: TokenSpan) -> Token {
         Self { value, kind, span }
     }
@@ -43,7 +45,7 @@ impl<'a> Token {
     fn is_number_start(target: &char, next: Option<&char>) -> bool {
         // A number starts with a digit or a '-' followed by a digit
         matches!(target, '0'..='9' | '.')
-            || (*target == '-' && next.map_or(false, |c| c == &'.' || c.is_ascii_digit()))
+            || (*target == '-' && next.is_some_and(|c| c == &'.' || c.is_ascii_digit()))
     }
 
     fn is_number(target: &char) -> bool {
@@ -51,15 +53,15 @@ impl<'a> Token {
     }
 
     fn is_word(target: &char) -> bool {
-        return target.is_ascii();
+        target.is_ascii()
     }
 
     fn is_string(target: &char) -> bool {
-        return target == &'"';
+        target == &'"'
     }
 
     fn is_newline(target: &char) -> bool {
-        return target == &'\n';
+        target == &'\n'
     }
 
     fn is_whitespace(target: &char) -> bool {
@@ -74,11 +76,112 @@ impl<'a> Token {
 pub struct Lexer<'a> {
     input: InputFile<'a>,
     span: Span,
+    filename: Rc<str>,
+    comments: bool,
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(input: InputFile<'a>, span: Span) -> Self {
-        Self { input, span }
+        let filename = Rc::from(input.name);
+        Self {
+            input,
+            span,
+            filename,
+            comments: false,
+        }
+    }
+
+    // Reads the rest of a word that starts with `buffer`, at column `col`.
+    fn word(&mut self, mut buffer: String, col: usize) -> Token {
+        while let Some(d) = self.input.content.peek() {
+            if Token::is_whitespace(d) {
+                break;
+            }
+            buffer.push(*d);
+            self.input.content.next();
+        }
+        self.span.col = col + buffer.chars().count();
+        Token::new(
+            buffer,
+            TokenKind::Word,
+            TokenSpan {
+                filename: Rc::clone(&self.filename),
+                line: self.span.line,
+                col,
+            },
+        )
+    }
+
+    // Reads the escape sequence after a `\` in a string literal: one of `\n`, `\t`, `\r`,
+    // `\0`, `\e`, `\\`, `\"`, `\xNN` (a byte up to 7F) or `\u{XXXX}` (any unicode scalar).
+    // Anything else is kept as it is written, backslash included, like before escapes existed.
+    fn escape(&mut self, buffer: &mut String) {
+        let simple = match self.input.content.peek() {
+            Some('n') => Some('\n'),
+            Some('t') => Some('\t'),
+            Some('r') => Some('\r'),
+            Some('0') => Some('\0'),
+            Some('e') => Some('\x1b'),
+            Some('\\') => Some('\\'),
+            Some('"') => Some('"'),
+            _ => None,
+        };
+        if let Some(c) = simple {
+            self.input.content.next();
+            self.span.col += 2;
+            buffer.push(c);
+            return;
+        }
+        let mut raw = String::from('\\');
+        let c = match self.input.content.next_if(|x| matches!(x, 'x' | 'u')) {
+            Some('x') => {
+                raw.push('x');
+                for _ in 0..2 {
+                    if let Some(d) = self.input.content.next_if(|d| d.is_ascii_hexdigit()) {
+                        raw.push(d);
+                    }
+                }
+                u8::from_str_radix(&raw[2..], 16)
+                    .ok()
+                    .filter(|n| raw.len() == 4 && n.is_ascii())
+                    .map(char::from)
+            }
+            Some(_) => {
+                raw.push('u');
+                if let Some(d) = self.input.content.next_if_eq(&'{') {
+                    raw.push(d);
+                    while let Some(d) = self.input.content.next_if(|d| d.is_ascii_hexdigit()) {
+                        raw.push(d);
+                    }
+                    if let Some(d) = self.input.content.next_if_eq(&'}') {
+                        raw.push(d);
+                    }
+                }
+                raw.strip_prefix("\\u{")
+                    .and_then(|x| x.strip_suffix('}'))
+                    .filter(|x| (1..=6).contains(&x.len()))
+                    .and_then(|x| u32::from_str_radix(x, 16).ok())
+                    .and_then(char::from_u32)
+            }
+            // the character after the backslash is read as usual
+            None => None,
+        };
+        self.span.col += raw.chars().count();
+        match c {
+            Some(c) => buffer.push(c),
+            None => buffer.push_str(&raw),
+        }
+    }
+
+    // The line the lexer is at, which is where the last token ended (strings can span lines).
+    pub fn line(&self) -> usize {
+        self.span.line
+    }
+
+    // Makes the lexer return comments as tokens instead of skipping them. The parser
+    // doesn't expect them, this is for tools that re-emit the source like `pile fmt`.
+    pub fn keep_comments(&mut self) {
+        self.comments = true;
     }
 }
 
@@ -97,8 +200,26 @@ impl<'a> Iterator for Lexer<'a> {
                     self.span.col += 1;
                     continue;
                 }
+                _ if Token::is_comment(&c) && self.comments => {
+                    let col = self.span.col;
+                    let mut buffer = String::from(c);
+                    // the newline is left for the next call, which counts the line
+                    while let Some(d) = self.input.content.next_if(|d| !Token::is_newline(d)) {
+                        buffer.push(d);
+                    }
+                    self.span.col += buffer.chars().count();
+                    return Some(Token::new(
+                        buffer,
+                        TokenKind::Comment,
+                        TokenSpan {
+                            filename: Rc::clone(&self.filename),
+                            line: self.span.line,
+                            col,
+                        },
+                    ));
+                }
                 _ if Token::is_comment(&c) => {
-                    while let Some(d) = self.input.content.next() {
+                    for d in self.input.content.by_ref() {
                         if Token::is_newline(&d) {
                             self.span.line += 1;
                             self.span.col = 1;
@@ -107,37 +228,47 @@ impl<'a> Iterator for Lexer<'a> {
                     }
                 }
                 _ if Token::is_string(&c) => {
-                    let col: usize = self.span.col;
+                    let (line, col) = (self.span.line, self.span.col);
                     let mut buffer = String::new();
-                    while let Some(d) = self.input.content.next() {
-                        if Token::is_string(&d) {
-                            break;
-                        } else if self.input.content.peek().is_none() {
+                    self.span.col += 1;
+                    loop {
+                        let Some(d) = self.input.content.next() else {
                             throw(
                                 "token error",
                                 &format!(
-                                    "expected closing quotation mark (\") for string literal \"{}\".",
-                                    buffer.clone() + &String::from(d)
+                                    "expected closing quotation mark (\") for string literal \"{buffer}\"."
                                 ),
                                 TokenSpan {
-                                    filename: self.input.name.to_string(),
-                                    line: self.span.line,
-                                    col: self.span.col + 2
+                                    filename: Rc::clone(&self.filename),
+                                    line,
+                                    col
                                 },
                                 Some("check if the string was left open unintentionally."),
-                                None,
+                                &[],
                             );
+                            unreachable!();
+                        };
+                        if Token::is_string(&d) {
+                            self.span.col += 1;
+                            break;
+                        } else if Token::is_newline(&d) {
+                            self.span.line += 1;
+                            self.span.col = 1;
+                            buffer.push(d);
+                        } else if d == '\\' {
+                            self.escape(&mut buffer);
+                        } else {
+                            self.span.col += 1;
+                            buffer.push(d);
                         }
-                        buffer.push(d);
                     }
-                    self.span.col += buffer.len() + 2; // +2 to consider both quote marks
                     return Some(Token::new(
                         buffer,
                         TokenKind::String,
                         TokenSpan {
-                            filename: self.input.name.to_string(),
-                            line: self.span.line,
-                            col: col,
+                            filename: Rc::clone(&self.filename),
+                            line,
+                            col,
                         },
                     ));
                 }
@@ -145,18 +276,23 @@ impl<'a> Iterator for Lexer<'a> {
                     let col = self.span.col;
                     let mut buffer = String::from(c);
                     while let Some(d) = self.input.content.peek() {
-                        if !Token::is_number(&d) {
-                            if !Token::is_whitespace(&d) {
+                        if !Token::is_number(d) {
+                            // words like `2dup` start with digits, like a number
+                            if d.is_ascii_alphabetic() && buffer.chars().all(|c| c.is_ascii_digit())
+                            {
+                                return Some(self.word(buffer, col));
+                            }
+                            if !Token::is_whitespace(d) {
                                 throw(
                                     "token error",
                                     &format!("invalid character `{d}` found in number literal."),
                                     TokenSpan {
-                                        filename: self.input.name.to_string(),
+                                        filename: Rc::clone(&self.filename),
                                         line: self.span.line,
                                         col: self.span.col + buffer.len(),
                                     },
                                     None,
-                                    None,
+                                    &[],
                                 );
                             }
                             break;
@@ -169,44 +305,27 @@ impl<'a> Iterator for Lexer<'a> {
                         buffer,
                         TokenKind::Number,
                         TokenSpan {
-                            filename: self.input.name.to_string(),
+                            filename: Rc::clone(&self.filename),
                             line: self.span.line,
-                            col: col,
+                            col,
                         },
                     ));
                 }
                 _ if Token::is_word(&c) => {
                     let col: usize = self.span.col;
-                    let mut buffer = String::from(c);
-                    while let Some(d) = self.input.content.peek() {
-                        if Token::is_whitespace(&d) {
-                            break;
-                        }
-                        buffer.push(*d);
-                        self.input.content.next();
-                    }
-                    self.span.col += buffer.len();
-                    return Some(Token::new(
-                        buffer,
-                        TokenKind::Word,
-                        TokenSpan {
-                            filename: self.input.name.to_string(),
-                            line: self.span.line,
-                            col: col,
-                        },
-                    ));
+                    return Some(self.word(String::from(c), col));
                 }
                 _ => {
                     throw(
                         "token error",
                         &format!("illegal character `{c}` found in file."),
                         TokenSpan {
-                            filename: self.input.name.to_string(),
+                            filename: Rc::clone(&self.filename),
                             line: self.span.line,
                             col: self.span.col,
                         },
                         None,
-                        None,
+                        &[],
                     );
                 }
             }
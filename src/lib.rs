@@ -0,0 +1,11 @@
+pub mod checker;
+pub mod cli;
+pub mod config;
+pub mod error;
+pub mod format;
+pub mod lexer;
+pub mod parser;
+pub mod project;
+pub mod runtime;
+pub mod standalone;
+pub mod toml;
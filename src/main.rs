@@ -1,15 +1,20 @@
-mod cli;
-mod error;
-mod lexer;
-mod parser;
-mod runtime;
-use cli::*;
-use lexer::*;
-use parser::*;
-use runtime::*;
+use pile::checker::Checker;
+use pile::cli::*;
+use pile::config::{self, Config};
+use pile::error;
+use pile::format;
+use pile::lexer::*;
+use pile::parser::*;
+use pile::project;
+use pile::runtime::*;
+use pile::standalone;
 use std::fs::File;
 use std::io::Read;
 
+// counts the memory in use, for --max-memory
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 fn read_file(path: &str) -> Option<String> {
@@ -17,18 +22,33 @@ fn read_file(path: &str) -> Option<String> {
         Ok(mut f) => {
             let mut xs = Vec::new();
             f.read_to_end(&mut xs).unwrap();
-            match String::from_utf8(xs) {
-                Ok(x) => return Some(x),
-                Err(_) => return None,
-            }
+            String::from_utf8(xs).ok()
         }
         Err(_) => None,
     }
 }
 
+// `-` is the program read from stdin
+fn read_source(path: &str) -> Option<String> {
+    if path == "-" {
+        let mut s = String::new();
+        std::io::stdin().read_to_string(&mut s).ok().map(|_| s)
+    } else {
+        read_file(path)
+    }
+}
+
+fn display_name(path: &str) -> &str {
+    if path == "-" {
+        "<stdin>"
+    } else {
+        path
+    }
+}
+
 fn parse(filename: &str, source: String) -> Result<ProgramTree, ParseError> {
     let f = InputFile {
-        name: filename,
+        name: display_name(filename),
         content: source.chars().peekable(),
     };
     let l = Lexer::new(f, Span { line: 1, col: 1 });
@@ -36,25 +56,170 @@ fn parse(filename: &str, source: String) -> Result<ProgramTree, ParseError> {
     p.parse()
 }
 
-fn run_program(p: ProgramTree) -> Result<(), RuntimeError> {
+fn load_config(a: &Arguments) -> Config {
+    config::load(a.config.as_deref()).unwrap_or_else(|e| {
+        error::fatal(&e);
+        Config::default()
+    })
+}
+
+fn run_program(p: ProgramTree, a: &Arguments, c: &Config) -> Result<(), RuntimeError> {
     let mut r = Runtime::new(&p);
+    catch_interrupts();
+    // flags take precedence over the configuration file
+    if let Some(t) = a.timeout.or(c.timeout) {
+        r.set_timeout(t);
+    }
+    if let Some(on) = c.colors {
+        r.set_colors(on);
+    }
+    if a.no_debug {
+        r.set_debug(false);
+    }
+    if let Some(name) = &a.entry {
+        r.set_entry(name);
+    }
+    if let Some(on) = c.warnings {
+        r.set_warnings(on);
+    }
+    r.set_args(a.args.clone());
+    r.set_tracing(a.trace);
+    if let Some(n) = a.max_call_depth.or(c.max_call_depth) {
+        r.set_max_call_depth(n);
+    }
+    if let Some(n) = a.max_instructions.or(c.max_instructions) {
+        r.set_max_instructions(n);
+    }
+    if let Some(n) = a.max_memory.or(c.max_memory) {
+        r.set_max_memory(n);
+    }
     r.run()
 }
 
-fn run(filename: &str, source: String) {
-    match parse(&filename, source) {
-        Ok(p) => {
-            if let Err(e) = run_program(p) {
-                error::runtime_error(e);
-            }
+// the same checks `run_program` makes before running anything, without running anything
+fn check(p: ProgramTree, c: &Config) {
+    match Checker::new(&p, Vec::new()).check() {
+        Ok(warnings) if c.warnings != Some(false) => warnings.into_iter().for_each(error::warning),
+        Ok(_) => {}
+        Err(e) => error::runtime_error(e),
+    }
+}
+
+fn run(a: &Arguments, source: String) {
+    let c = load_config(a);
+    // deep recursion needs more stack than the main thread has
+    let depth = a
+        .max_call_depth
+        .or(c.max_call_depth)
+        .unwrap_or(DEFAULT_MAX_CALL_DEPTH);
+    let result = std::thread::scope(|s| {
+        std::thread::Builder::new()
+            .stack_size(stack_size(depth))
+            .spawn_scoped(s, || interpret(a, &c, source))
+            .map(|t| t.join())
+    });
+    match result {
+        Ok(Ok(())) => {}
+        // the panic message was already printed by the thread
+        Ok(Err(_)) => std::process::exit(101),
+        Err(e) => error::fatal(&format!(
+            "couldn't reserve the stack for {} nested calls: {}. try a lower --max-call-depth.",
+            depth, e
+        )),
+    }
+}
+
+fn interpret(a: &Arguments, c: &Config, source: String) {
+    // libraries given before the file become part of the same program
+    let mut program = Vec::new();
+    for f in &a.preload {
+        let Some(s) = read_source(f) else {
+            show_usage();
+            return error::fatal(&format!("couldn't read file {}.", f));
+        };
+        match parse(f, s) {
+            Ok(p) => program.extend(p),
+            Err(e) => return error::parse_error(e),
         }
-        Err(e) => error::parse_error(e),
+    }
+    match parse(&a.filename, source) {
+        Ok(p) => program.extend(p),
+        Err(e) => return error::parse_error(e),
+    }
+    if a.check {
+        return check(program, c);
+    }
+    if let Err(e) = run_program(program, a, c) {
+        error::runtime_error(e);
+    }
+}
+
+// one token per line: where it is, its kind and its value (quoted, so whitespace shows)
+fn dump_tokens(filename: &str, source: &str) {
+    let f = InputFile {
+        name: display_name(filename),
+        content: source.chars().peekable(),
+    };
+    for t in Lexer::new(f, Span { line: 1, col: 1 }) {
+        println!(
+            "{}:{}:{}\t{:?}\t{:?}",
+            t.span.filename, t.span.line, t.span.col, t.kind, t.value
+        );
+    }
+}
+
+fn build(a: &Arguments, source: String) {
+    // the program is checked now so that the executable doesn't fail right away
+    if let Err(e) = parse(&a.filename, source.clone()) {
+        error::parse_error(e);
+    }
+    if a.filename == "-" && a.output.is_none() {
+        return error::fatal("a program read from stdin needs -o OUTPUT to be built.");
+    }
+    let output = a.output.clone().unwrap_or_else(|| {
+        let stem = std::path::Path::new(&a.filename)
+            .file_stem()
+            .unwrap_or_default();
+        format!("{}{}", stem.to_string_lossy(), std::env::consts::EXE_SUFFIX)
+    });
+    if let Err(e) = standalone::build(&output, display_name(&a.filename), &source) {
+        error::fatal(&e);
+    }
+    println!("built {}.", output);
+}
+
+fn format(a: &Arguments, source: String) {
+    // a file that doesn't parse is left as it is, its tokens may not mean what they seem
+    if let Err(e) = parse(&a.filename, source.clone()) {
+        error::parse_error(e);
+    }
+    let formatted = format::format(display_name(&a.filename), &source);
+    if a.filename == "-" {
+        // there is no file to rewrite
+        print!("{}", formatted);
+    } else if formatted != source {
+        if let Err(e) = std::fs::write(&a.filename, formatted) {
+            error::fatal(&format!("couldn't write {}: {}.", a.filename, e));
+        }
+        println!("formatted {}.", a.filename);
     }
 }
 
 fn main() {
+    // a standalone executable made by `pile build` runs its own program,
+    // all of its arguments are for that program
+    if let Some((filename, source)) = standalone::embedded() {
+        let a = Arguments {
+            filename,
+            args: std::env::args().skip(1).collect(),
+            ..Default::default()
+        };
+        run(&a, source);
+        return;
+    }
+
     match parse_arguments() {
-        Ok(a) => {
+        Ok(mut a) => {
             if a.show_help {
                 show_usage();
                 show_help();
@@ -66,11 +231,59 @@ fn main() {
                 std::process::exit(0);
             }
 
-            if let Some(source) = read_file(&a.filename) {
-                run(&a.filename, source);
+            if let Some(name) = &a.new_project {
+                if let Err(e) = project::create(name) {
+                    error::fatal(&e);
+                }
+                println!(
+                    "created project `{}`, run it with `pile run` inside of it.",
+                    name
+                );
+                std::process::exit(0);
+            }
+
+            if a.from_manifest {
+                let Some(path) = project::find_manifest() else {
+                    error::fatal(&format!(
+                        "no {} found in this directory or its parents.",
+                        project::MANIFEST
+                    ));
+                    return;
+                };
+                match project::read_manifest(&path) {
+                    Ok(m) => a.filename = m.entry.to_string_lossy().to_string(),
+                    Err(e) => error::fatal(&e),
+                }
+            }
+
+            let source = match a.eval.take() {
+                Some(code) => code,
+                None => match read_source(&a.filename) {
+                    Some(s) => s,
+                    None => {
+                        show_usage();
+                        return error::fatal(&format!(
+                            "couldn't read file {}.",
+                            display_name(&a.filename)
+                        ));
+                    }
+                },
+            };
+
+            if a.dump_tokens {
+                for f in &a.preload {
+                    match read_source(f) {
+                        Some(s) => dump_tokens(f, &s),
+                        None => error::fatal(&format!("couldn't read file {}.", f)),
+                    }
+                }
+                dump_tokens(&a.filename, &source);
+            } else if a.build {
+                build(&a, source);
+            } else if a.format {
+                format(&a, source);
             } else {
-                show_usage();
-                error::fatal(&format!("couldn't read file {}.", a.filename));
+                run(&a, source);
             }
         }
         Err(e) => {
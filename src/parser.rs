@@ -1,4 +1,6 @@
 use crate::lexer::{Lexer, Token, TokenKind, TokenSpan};
+use std::iter::Peekable;
+use std::rc::Rc;
 
 pub fn is_op(value: &str) -> bool {
     matches!(
@@ -8,6 +10,13 @@ pub fn is_op(value: &str) -> bool {
             | "swap"
             | "over"
             | "rot"
+            | "pick"
+            | "roll"
+            | "2dup"
+            | "2swap"
+            | "2drop"
+            | "nip"
+            | "tuck"
             | "trace"
             | "+"
             | "-"
@@ -31,13 +40,32 @@ pub fn is_op(value: &str) -> bool {
 pub fn is_reserved_word(value: &str) -> bool {
     matches!(
         value,
-        "if" | "loop" | "proc" | "end" | "else" | "def" | "stop"
+        "if" | "loop"
+            | "proc"
+            | "end"
+            | "else"
+            | "def"
+            | "stop"
+            | "times"
+            | "until"
+            | "do"
+            | "elif"
+            | "typecase"
+            | "case"
+            | "INF"
+            | "NAN"
+            | "debug"
+            | "quote"
+            | "try"
+            | "catch"
+            | "for"
+            | "while"
     )
 }
 
 // don't know if this really works in all possibilities, i have to test it
 pub fn is_valid_identifier(value: &str) -> bool {
-    !value.chars().next().map_or(false, |c| c.is_digit(10))
+    !value.chars().next().is_some_and(|c| c.is_ascii_digit())
         && value.chars().all(|c| c.is_alphanumeric() || c == '_')
         && !is_reserved_word(value)
         && !is_op(value)
@@ -67,28 +95,152 @@ pub enum OpKind {
     Trace,
     Dup,
     Rot,
+    Pick,    // `n pick` copies the element n places below the top (`0 pick` is `dup`)
+    Roll,    // `n roll` moves the element n places below the top to the top (`2 roll` is `rot`)
+    TwoDup,  // a b -- a b a b
+    TwoSwap, // a b c d -- c d a b
+    TwoDrop, // a b --
+    Nip,     // a b -- b
+    Tuck,    // a b -- b a b
     Drop,
     Stop,
 }
 
+impl std::fmt::Display for OpKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let word = match self {
+            OpKind::Add => "+",
+            OpKind::Sub => "-",
+            OpKind::Mul => "*",
+            OpKind::Div => "/",
+            OpKind::Mod => "%",
+            OpKind::Exp => "**",
+            OpKind::Gt => ">",
+            OpKind::Lt => "<",
+            OpKind::Eq => "=",
+            OpKind::Ge => ">=",
+            OpKind::Le => "<=",
+            OpKind::Ne => "!=",
+            OpKind::Shl => "<<",
+            OpKind::Shr => ">>",
+            OpKind::Bor => "|",
+            OpKind::Band => "&",
+            OpKind::BNot => "~",
+            OpKind::Swap => "swap",
+            OpKind::Over => "over",
+            OpKind::Trace => "trace",
+            OpKind::Dup => "dup",
+            OpKind::Rot => "rot",
+            OpKind::Pick => "pick",
+            OpKind::Roll => "roll",
+            OpKind::TwoDup => "2dup",
+            OpKind::TwoSwap => "2swap",
+            OpKind::TwoDrop => "2drop",
+            OpKind::Nip => "nip",
+            OpKind::Tuck => "tuck",
+            OpKind::Drop => "drop",
+            OpKind::Stop => "stop",
+        };
+        write!(f, "{word}")
+    }
+}
+
+// declared stack effect of a procedure, e.g. `( a b -- a b a b )`
+#[derive(Debug)]
+pub struct StackEffect {
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+}
+
+impl std::fmt::Display for StackEffect {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "(")?;
+        for x in &self.inputs {
+            write!(f, " {x}")?;
+        }
+        write!(f, " --")?;
+        for x in &self.outputs {
+            write!(f, " {x}")?;
+        }
+        write!(f, " )")
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub enum Node {
     Number(f64, TokenSpan),
     String(String, TokenSpan),
-    Proc(String, Vec<Node>, TokenSpan),
+    Proc(String, Option<StackEffect>, Vec<Node>, TokenSpan),
     Def(String, Vec<Node>, TokenSpan),
     If(Vec<Node>, Option<Vec<Node>>, TokenSpan),
     Loop(Vec<Node>, TokenSpan),
+    Times(Vec<Node>, TokenSpan),
+    For(String, Vec<Node>, TokenSpan), // `for NAME ... end`: like `times`, with a named counter
+    Until(Vec<Node>, Vec<Node>, TokenSpan),
+    While(Vec<Node>, Vec<Node>, TokenSpan),
+    TypeCase(Vec<(String, Vec<Node>)>, Option<Vec<Node>>, TokenSpan),
+    Try(Vec<Node>, Vec<Node>, TokenSpan), // `try ... catch ... end`
     Operation(OpKind, TokenSpan),
     Word(String, TokenSpan),
+    Debug(Option<String>, TokenSpan), // `debug` or `debug:label`
+    Quote(Rc<Vec<Node>>, TokenSpan),  // a block pushed as a value, run with `call`
+}
+
+// the word a node starts with in the source, blocks are shown by their keyword
+impl std::fmt::Display for Node {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Node::Number(n, _) => write!(f, "{n}"),
+            Node::String(x, _) => write!(f, "{x:?}"),
+            Node::Proc(n, _, _, _) => write!(f, "proc {n}"),
+            Node::Def(n, _, _) => write!(f, "def {n}"),
+            Node::If(_, _, _) => write!(f, "if"),
+            Node::Loop(_, _) => write!(f, "loop"),
+            Node::Times(_, _) => write!(f, "times"),
+            Node::For(n, _, _) => write!(f, "for {n}"),
+            Node::Until(_, _, _) => write!(f, "until"),
+            Node::While(_, _, _) => write!(f, "while"),
+            Node::TypeCase(_, _, _) => write!(f, "typecase"),
+            Node::Try(_, _, _) => write!(f, "try"),
+            Node::Operation(o, _) => write!(f, "{o}"),
+            Node::Word(w, _) => write!(f, "{w}"),
+            Node::Debug(None, _) => write!(f, "debug"),
+            Node::Debug(Some(l), _) => write!(f, "debug:{l}"),
+            Node::Quote(_, _) => write!(f, "quote"),
+        }
+    }
+}
+
+impl Node {
+    pub fn span(&self) -> &TokenSpan {
+        match self {
+            Node::Number(_, s)
+            | Node::String(_, s)
+            | Node::Proc(_, _, _, s)
+            | Node::Def(_, _, s)
+            | Node::If(_, _, s)
+            | Node::Loop(_, s)
+            | Node::Times(_, s)
+            | Node::For(_, _, s)
+            | Node::Until(_, _, s)
+            | Node::While(_, _, s)
+            | Node::TypeCase(_, _, s)
+            | Node::Try(_, _, s)
+            | Node::Operation(_, s)
+            | Node::Word(_, s)
+            | Node::Debug(_, s)
+            | Node::Quote(_, s) => s,
+        }
+    }
 }
 
 pub type ProgramTree = Vec<Node>;
 
 pub struct Parser<'a> {
-    lexer: Lexer<'a>,
+    lexer: Peekable<Lexer<'a>>,
     current_span: Option<TokenSpan>,
+    breakable: usize, // how many blocks around the current token allow `stop`
 }
 
 #[derive(Debug)]
@@ -97,13 +249,15 @@ pub enum ParseError {
     UnexpectedEOF(TokenSpan, String),
     UnterminatedBlock(TokenSpan, String),
     UnmatchedBlock(TokenSpan),
+    StopOutsideLoop(TokenSpan),
 }
 
 impl<'a> Parser<'a> {
     pub fn new(lexer: Lexer<'a>) -> Self {
         Self {
-            lexer: lexer,
+            lexer: lexer.peekable(),
             current_span: None,
+            breakable: 0,
         }
     }
 
@@ -120,10 +274,19 @@ impl<'a> Parser<'a> {
         match token.kind {
             TokenKind::Number => Ok(Node::Number(token.value.parse().unwrap(), token.span)),
             TokenKind::Word => match token.value.as_str() {
-                "proc" => self.parse_proc(),
+                // procedures can be called from inside a loop, so `stop` is allowed in them too
+                "proc" => self.parse_breakable(|p| p.parse_proc()),
                 "def" => self.parse_def(),
                 "if" => self.parse_if(),
-                "loop" => self.parse_loop(),
+                "loop" => self.parse_breakable(|p| p.parse_loop()),
+                "times" => self.parse_breakable(|p| p.parse_times(token.span)),
+                "for" => self.parse_breakable(|p| p.parse_for(token.span)),
+                "until" => self.parse_breakable(|p| p.parse_until(token.span)),
+                "while" => self.parse_breakable(|p| p.parse_while(token.span)),
+                "typecase" => self.parse_typecase(token.span),
+                "try" => self.parse_try(token.span),
+                // like procedures, quotations may be called from inside a loop
+                "quote" => self.parse_breakable(|p| p.parse_quote(token.span)),
                 "end" => Err(ParseError::UnmatchedBlock(
                     self.current_span
                         .clone()
@@ -151,7 +314,23 @@ impl<'a> Parser<'a> {
                 "swap" => Ok(Node::Operation(OpKind::Swap, token.span)),
                 "over" => Ok(Node::Operation(OpKind::Over, token.span)),
                 "rot" => Ok(Node::Operation(OpKind::Rot, token.span)),
+                "pick" => Ok(Node::Operation(OpKind::Pick, token.span)),
+                "roll" => Ok(Node::Operation(OpKind::Roll, token.span)),
+                "2dup" => Ok(Node::Operation(OpKind::TwoDup, token.span)),
+                "2swap" => Ok(Node::Operation(OpKind::TwoSwap, token.span)),
+                "2drop" => Ok(Node::Operation(OpKind::TwoDrop, token.span)),
+                "nip" => Ok(Node::Operation(OpKind::Nip, token.span)),
+                "tuck" => Ok(Node::Operation(OpKind::Tuck, token.span)),
                 "trace" => Ok(Node::Operation(OpKind::Trace, token.span)),
+                // special floating point values, so they don't need to be computed
+                "INF" => Ok(Node::Number(f64::INFINITY, token.span)),
+                "-INF" => Ok(Node::Number(f64::NEG_INFINITY, token.span)),
+                "NAN" => Ok(Node::Number(f64::NAN, token.span)),
+                "debug" => Ok(Node::Debug(None, token.span)),
+                x if x.len() > 6 && x.starts_with("debug:") => {
+                    Ok(Node::Debug(Some(x[6..].to_string()), token.span))
+                }
+                "stop" if self.breakable == 0 => Err(ParseError::StopOutsideLoop(token.span)),
                 "stop" => Ok(Node::Operation(OpKind::Stop, token.span)),
                 x if is_valid_identifier(x) => Ok(Node::Word(token.value, token.span)),
                 _ => Err(ParseError::UnexpectedToken(
@@ -161,9 +340,21 @@ impl<'a> Parser<'a> {
                 )),
             },
             TokenKind::String => Ok(Node::String(token.value, token.span)),
+            TokenKind::Comment => unreachable!("the parser's lexer skips comments"),
         }
     }
 
+    // Parses a block in which `stop` can be used.
+    fn parse_breakable<F>(&mut self, f: F) -> Result<Node, ParseError>
+    where
+        F: FnOnce(&mut Self) -> Result<Node, ParseError>,
+    {
+        self.breakable += 1;
+        let node = f(self);
+        self.breakable -= 1;
+        node
+    }
+
     fn parse_proc(&mut self) -> Result<Node, ParseError> {
         let proc_name = self.lexer.next().ok_or_else(|| {
             let span = self.current_span.clone().unwrap();
@@ -178,11 +369,16 @@ impl<'a> Parser<'a> {
             ));
         }
 
+        let effect = match self.lexer.next_if(|t| t.value == "(") {
+            Some(t) => Some(self.parse_stack_effect(t.span)?),
+            None => None,
+        };
+
         let mut body = Vec::new();
 
         while let Some(token) = self.lexer.next() {
             if token.value == "end" {
-                return Ok(Node::Proc(proc_name.value, body, token.span));
+                return Ok(Node::Proc(proc_name.value, effect, body, proc_name.span));
             }
             body.push(self.parse_expr(token)?);
         }
@@ -193,10 +389,46 @@ impl<'a> Parser<'a> {
         ))
     }
 
+    fn parse_stack_effect(&mut self, span: TokenSpan) -> Result<StackEffect, ParseError> {
+        let mut inputs = Vec::new();
+        let mut outputs = None;
+
+        for token in self.lexer.by_ref() {
+            match (token.value.as_str(), &mut outputs) {
+                ("--", None) => outputs = Some(Vec::new()),
+                (")", Some(_)) => {
+                    return Ok(StackEffect {
+                        inputs,
+                        outputs: outputs.unwrap(),
+                    });
+                }
+                ("--" | ")", _) => {
+                    return Err(ParseError::UnexpectedToken(
+                        token.span,
+                        token.value,
+                        if outputs.is_none() {
+                            "`--`"
+                        } else {
+                            "output name or `)`"
+                        }
+                        .to_string(),
+                    ));
+                }
+                (_, None) => inputs.push(token.value),
+                (_, Some(xs)) => xs.push(token.value),
+            }
+        }
+
+        Err(ParseError::UnexpectedEOF(
+            span,
+            "end of stack effect (`)`)".to_string(),
+        ))
+    }
+
     fn parse_def(&mut self) -> Result<Node, ParseError> {
         let def_name = self.lexer.next().ok_or_else(|| {
             let span = self.current_span.clone().unwrap_or_else(|| TokenSpan {
-                filename: "unknown".to_string(),
+                filename: "unknown".into(),
                 line: 0,
                 col: 0,
             });
@@ -215,7 +447,7 @@ impl<'a> Parser<'a> {
 
         while let Some(token) = self.lexer.next() {
             if token.value == "end" {
-                return Ok(Node::Def(def_name.value, body, token.span));
+                return Ok(Node::Def(def_name.value, body, def_name.span));
             }
             body.push(self.parse_expr(token)?);
         }
@@ -243,6 +475,31 @@ impl<'a> Parser<'a> {
                     token.span.clone(),
                     "else".to_string(),
                 ));
+            } else if token.value == "elif" {
+                // `elif <condition> do ...` is the same as `else <condition> if ... end`
+                let mut else_block = Vec::new();
+                while let Some(token) = self.lexer.next() {
+                    if token.value == "do" {
+                        let rest = self.parse_if()?;
+                        let span = match &rest {
+                            Node::If(_, _, s) => s.clone(),
+                            _ => unreachable!(),
+                        };
+                        else_block.push(rest);
+                        return Ok(Node::If(if_body, Some(else_block), span));
+                    } else if token.value == "end" {
+                        return Err(ParseError::UnexpectedToken(
+                            token.span,
+                            token.value,
+                            "`do`".to_string(),
+                        ));
+                    }
+                    else_block.push(self.parse_expr(token)?);
+                }
+                return Err(ParseError::UnterminatedBlock(
+                    token.span.clone(),
+                    "elif".to_string(),
+                ));
             } else if token.value == "end" {
                 return Ok(Node::If(if_body, else_body, token.span));
             }
@@ -270,4 +527,175 @@ impl<'a> Parser<'a> {
             "loop".to_string(),
         ))
     }
+
+    fn parse_quote(&mut self, span: TokenSpan) -> Result<Node, ParseError> {
+        let mut body = Vec::new();
+
+        while let Some(token) = self.lexer.next() {
+            if token.value == "end" {
+                return Ok(Node::Quote(Rc::new(body), span));
+            }
+            body.push(self.parse_expr(token)?);
+        }
+
+        Err(ParseError::UnterminatedBlock(span, "quote".to_string()))
+    }
+
+    fn parse_times(&mut self, span: TokenSpan) -> Result<Node, ParseError> {
+        let mut body = Vec::new();
+
+        while let Some(token) = self.lexer.next() {
+            if token.value == "end" {
+                return Ok(Node::Times(body, span));
+            }
+            body.push(self.parse_expr(token)?);
+        }
+
+        Err(ParseError::UnterminatedBlock(span, "times".to_string()))
+    }
+
+    fn parse_for(&mut self, span: TokenSpan) -> Result<Node, ParseError> {
+        let name = self.lexer.next().ok_or_else(|| {
+            ParseError::UnexpectedEOF(span.clone(), "name of the counter".to_string())
+        })?;
+
+        if !is_valid_identifier(&name.value) {
+            return Err(ParseError::UnexpectedToken(
+                name.span,
+                name.value,
+                "name of the counter".to_string(),
+            ));
+        }
+
+        let mut body = Vec::new();
+
+        while let Some(token) = self.lexer.next() {
+            if token.value == "end" {
+                return Ok(Node::For(name.value, body, span));
+            }
+            body.push(self.parse_expr(token)?);
+        }
+
+        Err(ParseError::UnterminatedBlock(span, "for".to_string()))
+    }
+
+    fn parse_until(&mut self, span: TokenSpan) -> Result<Node, ParseError> {
+        let mut cond = Vec::new();
+
+        while let Some(token) = self.lexer.next() {
+            if token.value == "do" {
+                let mut body = Vec::new();
+                while let Some(token) = self.lexer.next() {
+                    if token.value == "end" {
+                        return Ok(Node::Until(cond, body, span));
+                    }
+                    body.push(self.parse_expr(token)?);
+                }
+                return Err(ParseError::UnterminatedBlock(span, "until".to_string()));
+            } else if token.value == "end" {
+                return Err(ParseError::UnexpectedToken(
+                    token.span,
+                    token.value,
+                    "`do`".to_string(),
+                ));
+            }
+            cond.push(self.parse_expr(token)?);
+        }
+
+        Err(ParseError::UnterminatedBlock(span, "until".to_string()))
+    }
+
+    fn parse_try(&mut self, span: TokenSpan) -> Result<Node, ParseError> {
+        let mut body = Vec::new();
+
+        while let Some(token) = self.lexer.next() {
+            if token.value == "catch" {
+                let mut handler = Vec::new();
+                while let Some(token) = self.lexer.next() {
+                    if token.value == "end" {
+                        return Ok(Node::Try(body, handler, span));
+                    }
+                    handler.push(self.parse_expr(token)?);
+                }
+                return Err(ParseError::UnterminatedBlock(span, "try".to_string()));
+            } else if token.value == "end" {
+                return Err(ParseError::UnexpectedToken(
+                    token.span,
+                    token.value,
+                    "`catch`".to_string(),
+                ));
+            }
+            body.push(self.parse_expr(token)?);
+        }
+
+        Err(ParseError::UnterminatedBlock(span, "try".to_string()))
+    }
+
+    fn parse_while(&mut self, span: TokenSpan) -> Result<Node, ParseError> {
+        let mut cond = Vec::new();
+
+        while let Some(token) = self.lexer.next() {
+            if token.value == "do" {
+                let mut body = Vec::new();
+                while let Some(token) = self.lexer.next() {
+                    if token.value == "end" {
+                        return Ok(Node::While(cond, body, span));
+                    }
+                    body.push(self.parse_expr(token)?);
+                }
+                return Err(ParseError::UnterminatedBlock(span, "while".to_string()));
+            } else if token.value == "end" {
+                return Err(ParseError::UnexpectedToken(
+                    token.span,
+                    token.value,
+                    "`do`".to_string(),
+                ));
+            }
+            cond.push(self.parse_expr(token)?);
+        }
+
+        Err(ParseError::UnterminatedBlock(span, "while".to_string()))
+    }
+
+    fn parse_typecase(&mut self, span: TokenSpan) -> Result<Node, ParseError> {
+        let mut cases: Vec<(String, Vec<Node>)> = Vec::new();
+        let mut else_body: Option<Vec<Node>> = None;
+
+        while let Some(token) = self.lexer.next() {
+            match token.value.as_str() {
+                "case" if else_body.is_none() => {
+                    let kind = self.lexer.next().ok_or_else(|| {
+                        ParseError::UnexpectedEOF(token.span.clone(), "type name".to_string())
+                    })?;
+                    if !matches!(kind.value.as_str(), "number" | "string" | "quote") {
+                        return Err(ParseError::UnexpectedToken(
+                            kind.span,
+                            kind.value,
+                            "type name (`number`, `string` or `quote`)".to_string(),
+                        ));
+                    }
+                    cases.push((kind.value, Vec::new()));
+                }
+                "else" if else_body.is_none() => else_body = Some(Vec::new()),
+                "end" => return Ok(Node::TypeCase(cases, else_body, span)),
+                _ => {
+                    if cases.is_empty() && else_body.is_none() {
+                        return Err(ParseError::UnexpectedToken(
+                            token.span,
+                            token.value,
+                            "`case`".to_string(),
+                        ));
+                    }
+                    let node = self.parse_expr(token)?;
+                    match (&mut else_body, cases.last_mut()) {
+                        (Some(body), _) => body.push(node),
+                        (None, Some((_, body))) => body.push(node),
+                        (None, None) => unreachable!(),
+                    }
+                }
+            }
+        }
+
+        Err(ParseError::UnterminatedBlock(span, "typecase".to_string()))
+    }
 }
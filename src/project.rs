@@ -0,0 +1,74 @@
+// Pile projects: a directory with a `pile.toml` manifest, created by `pile new` and
+// run by `pile run`.
+use crate::toml::{self, Value};
+use std::path::{Path, PathBuf};
+
+pub const MANIFEST: &str = "pile.toml";
+
+pub struct Manifest {
+    pub name: String,
+    pub version: String,
+    pub entry: PathBuf, // the file `pile run` runs (relative to the manifest's directory)
+}
+
+// Creates the directory `name` with a manifest and a hello world entry point.
+pub fn create(name: &str) -> Result<(), String> {
+    let dir = Path::new(name);
+    if dir.exists() {
+        return Err(format!("`{}` already exists.", name));
+    }
+    let project = dir
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| name.to_string());
+    let manifest = format!(
+        "[project]\nname = \"{}\"\nversion = \"0.1.0\"\nentry = \"main.pile\"\n",
+        project
+    );
+    std::fs::create_dir_all(dir)
+        .and_then(|_| std::fs::write(dir.join(MANIFEST), manifest))
+        .and_then(|_| std::fs::write(dir.join("main.pile"), "\"Hello, world!\" println\n"))
+        .map_err(|e| format!("couldn't create project `{}`: {}.", name, e))
+}
+
+// Looks for a manifest in the current directory and then in its parents.
+pub fn find_manifest() -> Option<PathBuf> {
+    let cwd = std::env::current_dir().ok()?;
+    cwd.ancestors()
+        .map(|d| d.join(MANIFEST))
+        .find(|m| m.is_file())
+}
+
+pub fn read_manifest(path: &Path) -> Result<Manifest, String> {
+    let mut name = None;
+    let mut version = None;
+    let mut entry = None;
+
+    for e in toml::read(path)? {
+        let value = match e.value {
+            Value::String(s) => s,
+            _ => {
+                return Err(format!(
+                    "{}:{}: expected a quoted string.",
+                    path.display(),
+                    e.line
+                ))
+            }
+        };
+        match (e.section.as_str(), e.key.as_str()) {
+            ("project", "name") => name = Some(value),
+            ("project", "version") => version = Some(value),
+            ("project", "entry") => entry = Some(value),
+            // unknown keys are left for newer versions of pile
+            _ => {}
+        }
+    }
+
+    let missing = |k: &str| format!("{}: missing `{}` in [project].", path.display(), k);
+    let dir = path.parent().unwrap_or(Path::new("."));
+    Ok(Manifest {
+        name: name.ok_or_else(|| missing("name"))?,
+        version: version.ok_or_else(|| missing("version"))?,
+        entry: dir.join(entry.ok_or_else(|| missing("entry"))?),
+    })
+}
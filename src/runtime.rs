@@ -1,17 +1,59 @@
 use crate::{
+    checker::Checker,
+    error::{error_message, warning},
     lexer::TokenSpan,
-    parser::{Node, OpKind, ProgramTree},
+    parser::{Node, OpKind, ProgramTree, StackEffect},
 };
 use std::{
-    collections::VecDeque,
-    io::{Read, Write},
+    alloc::{GlobalAlloc, Layout, System},
+    collections::{BTreeMap, HashMap, VecDeque},
+    io::{BufRead, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
     str::FromStr,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::{Duration, Instant, SystemTime},
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Data {
     String(String),
     Number(f64),
+    Quote(Rc<Vec<Node>>), // a block of code (`quote ... end`), run with `call`
+}
+
+impl PartialEq for Data {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Data::String(a), Data::String(b)) => a == b,
+            (Data::Number(a), Data::Number(b)) => a == b,
+            // quotations are only equal to themselves (or copies of them)
+            (Data::Quote(a), Data::Quote(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl Data {
+    // negative numbers, zero and empty strings are false, anything else is true
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Data::Number(n) => *n > 0.0,
+            Data::String(s) => !s.is_empty(),
+            Data::Quote(_) => true,
+        }
+    }
+}
+
+impl Data {
+    // how `trace` and friends show a value
+    pub fn describe(&self) -> String {
+        match self {
+            Data::Number(n) => format!("number {}", n),
+            Data::String(s) => format!("string \"{}\"", s),
+            Data::Quote(_) => "quote".to_string(),
+        }
+    }
 }
 
 impl std::fmt::Display for Data {
@@ -19,20 +61,122 @@ impl std::fmt::Display for Data {
         match *self {
             Data::String(_) => write!(f, "string"),
             Data::Number(_) => write!(f, "number"),
+            Data::Quote(_) => write!(f, "quote"),
+        }
+    }
+}
+
+// conversions between Rust values and Pile values for programs embedding Pile.
+// `TryFrom` gives back the original value when it doesn't fit the requested type.
+
+impl From<f64> for Data {
+    fn from(n: f64) -> Self {
+        Data::Number(n)
+    }
+}
+
+impl From<i32> for Data {
+    fn from(n: i32) -> Self {
+        Data::Number(n as f64)
+    }
+}
+
+impl From<i64> for Data {
+    fn from(n: i64) -> Self {
+        Data::Number(n as f64)
+    }
+}
+
+impl From<bool> for Data {
+    fn from(b: bool) -> Self {
+        Data::Number(b as i32 as f64)
+    }
+}
+
+impl From<&str> for Data {
+    fn from(s: &str) -> Self {
+        Data::String(s.to_string())
+    }
+}
+
+impl From<String> for Data {
+    fn from(s: String) -> Self {
+        Data::String(s)
+    }
+}
+
+impl TryFrom<Data> for f64 {
+    type Error = Data;
+
+    fn try_from(d: Data) -> Result<Self, Self::Error> {
+        match d {
+            Data::Number(n) => Ok(n),
+            x => Err(x),
+        }
+    }
+}
+
+impl TryFrom<Data> for i64 {
+    type Error = Data;
+
+    fn try_from(d: Data) -> Result<Self, Self::Error> {
+        match d {
+            // `as` would saturate, so only whole numbers that fit are converted
+            // (i64::MAX as f64 rounds up to 2^63, which doesn't fit)
+            Data::Number(n)
+                if n.is_finite()
+                    && n.fract() == 0.0
+                    && n >= i64::MIN as f64
+                    && n < i64::MAX as f64 =>
+            {
+                Ok(n as i64)
+            }
+            x => Err(x),
+        }
+    }
+}
+
+impl TryFrom<Data> for bool {
+    type Error = Data;
+
+    fn try_from(d: Data) -> Result<Self, Self::Error> {
+        Ok(d.is_truthy())
+    }
+}
+
+impl TryFrom<Data> for String {
+    type Error = Data;
+
+    fn try_from(d: Data) -> Result<Self, Self::Error> {
+        match d {
+            Data::String(s) => Ok(s),
+            x => Err(x),
         }
     }
 }
 
 #[derive(Debug)]
-pub struct Procedure<'a>(String, &'a Vec<Node>);
+pub struct Procedure<'a>(String, &'a Vec<Node>, Option<&'a StackEffect>);
 
 #[derive(Debug)]
 pub struct Definition(String, Data);
 
+// a word implemented by the program embedding Pile (see `Runtime::register`)
+pub type HostFunction<'a> = Rc<dyn Fn(&mut Runtime<'a>) -> Result<(), String> + 'a>;
+
+pub struct HostProcedure<'a>(String, HostFunction<'a>);
+
+impl std::fmt::Debug for HostProcedure<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "HostProcedure({:?})", self.0)
+    }
+}
+
 #[derive(Debug)]
 pub struct Namespace<'a> {
     pub procs: Vec<Procedure<'a>>,
     pub defs: Vec<Definition>,
+    pub hosts: Vec<HostProcedure<'a>>,
 }
 
 // stack operations:
@@ -116,6 +260,62 @@ pub enum Builtin {
     Exit,
     ToNumber,
     ToString,
+    Cmd,
+    CopyFile,
+    MoveFile,
+    Symlink,
+    ReadLink,
+    WatchPath,
+    Platform,
+    Hostname,
+    Username,
+    CpuCount,
+    InputSecret,
+    ReadPrompt,
+    Stylize,
+    RedLn,
+    GreenLn,
+    YellowLn,
+    MemStats,
+    PerfCounter,
+    Measure,
+    Sum,
+    Mean,
+    Median,
+    MinOf,
+    MaxOf,
+    StdDev,
+    KvOpen,
+    KvGet,
+    KvSet,
+    KvDel,
+    KvHas,
+    IsNan,
+    IsInf,
+    IsFinite,
+    Call,
+    Sqrt,
+    Sin,
+    Cos,
+    Tan,
+    Log,
+    Exp,
+    Random,
+    RandInt,
+    Time,
+    Clock,
+    StrFTime,
+    Argc,
+    Argv,
+    Exists,
+    IsDir,
+    Remove,
+    MkDir,
+    ListDir,
+    Depth,
+    Sort,
+    SortBy,
+    Format,
 }
 
 impl std::fmt::Display for Builtin {
@@ -130,12 +330,674 @@ impl std::fmt::Display for Builtin {
             Builtin::Exit => write!(f, "exit"),
             Builtin::ToNumber => write!(f, "tonumber"),
             Builtin::ToString => write!(f, "tostring"),
+            Builtin::Cmd => write!(f, "cmd"),
+            Builtin::CopyFile => write!(f, "copyfile"),
+            Builtin::MoveFile => write!(f, "movefile"),
+            Builtin::Symlink => write!(f, "symlink"),
+            Builtin::ReadLink => write!(f, "readlink"),
+            Builtin::WatchPath => write!(f, "watchpath"),
+            Builtin::Platform => write!(f, "platform"),
+            Builtin::Hostname => write!(f, "hostname"),
+            Builtin::Username => write!(f, "username"),
+            Builtin::CpuCount => write!(f, "cpucount"),
+            Builtin::InputSecret => write!(f, "inputsecret"),
+            Builtin::ReadPrompt => write!(f, "readprompt"),
+            Builtin::Stylize => write!(f, "stylize"),
+            Builtin::RedLn => write!(f, "redln"),
+            Builtin::GreenLn => write!(f, "greenln"),
+            Builtin::YellowLn => write!(f, "yellowln"),
+            Builtin::MemStats => write!(f, "memstats"),
+            Builtin::PerfCounter => write!(f, "perfcounter"),
+            Builtin::Measure => write!(f, "measure"),
+            Builtin::Sum => write!(f, "sum"),
+            Builtin::Mean => write!(f, "mean"),
+            Builtin::Median => write!(f, "median"),
+            Builtin::MinOf => write!(f, "minof"),
+            Builtin::MaxOf => write!(f, "maxof"),
+            Builtin::StdDev => write!(f, "stddev"),
+            Builtin::KvOpen => write!(f, "kvopen"),
+            Builtin::KvGet => write!(f, "kvget"),
+            Builtin::KvSet => write!(f, "kvset"),
+            Builtin::KvDel => write!(f, "kvdel"),
+            Builtin::KvHas => write!(f, "kvhas"),
+            Builtin::IsNan => write!(f, "isnan"),
+            Builtin::IsInf => write!(f, "isinf"),
+            Builtin::IsFinite => write!(f, "isfinite"),
+            Builtin::Call => write!(f, "call"),
+            Builtin::Sqrt => write!(f, "sqrt"),
+            Builtin::Sin => write!(f, "sin"),
+            Builtin::Cos => write!(f, "cos"),
+            Builtin::Tan => write!(f, "tan"),
+            Builtin::Log => write!(f, "log"),
+            Builtin::Exp => write!(f, "exp"),
+            Builtin::Random => write!(f, "random"),
+            Builtin::RandInt => write!(f, "randint"),
+            Builtin::Time => write!(f, "time"),
+            Builtin::Clock => write!(f, "clock"),
+            Builtin::StrFTime => write!(f, "strftime"),
+            Builtin::Argc => write!(f, "argc"),
+            Builtin::Argv => write!(f, "argv"),
+            Builtin::Exists => write!(f, "exists"),
+            Builtin::IsDir => write!(f, "isdir"),
+            Builtin::Remove => write!(f, "remove"),
+            Builtin::MkDir => write!(f, "mkdir"),
+            Builtin::ListDir => write!(f, "listdir"),
+            Builtin::Depth => write!(f, "depth"),
+            Builtin::Sort => write!(f, "sort"),
+            Builtin::SortBy => write!(f, "sortby"),
+            Builtin::Format => write!(f, "format"),
+        }
+    }
+}
+
+impl Builtin {
+    // The builtins Pile started with. They take precedence over procedures and definitions
+    // with the same name, the ones added later don't, so that they don't break programs
+    // that already used their names.
+    pub fn is_reserved(&self) -> bool {
+        matches!(
+            self,
+            Builtin::Print
+                | Builtin::Println
+                | Builtin::EPrint
+                | Builtin::EPrintln
+                | Builtin::Read
+                | Builtin::Readln
+                | Builtin::Exit
+                | Builtin::ToNumber
+                | Builtin::ToString
+        )
+    }
+
+    pub fn from_word(word: &str) -> Option<Self> {
+        match word {
+            "print" => Some(Builtin::Print),
+            "println" => Some(Builtin::Println),
+            "eprintln" => Some(Builtin::EPrintln),
+            "eprint" => Some(Builtin::EPrint),
+            "read" => Some(Builtin::Read),
+            "readln" => Some(Builtin::Readln),
+            "exit" => Some(Builtin::Exit),
+            "tonumber" => Some(Builtin::ToNumber),
+            "tostring" => Some(Builtin::ToString),
+            "cmd" => Some(Builtin::Cmd),
+            "copyfile" => Some(Builtin::CopyFile),
+            "movefile" => Some(Builtin::MoveFile),
+            "symlink" => Some(Builtin::Symlink),
+            "readlink" => Some(Builtin::ReadLink),
+            "watchpath" => Some(Builtin::WatchPath),
+            "platform" => Some(Builtin::Platform),
+            "hostname" => Some(Builtin::Hostname),
+            "username" => Some(Builtin::Username),
+            "cpucount" => Some(Builtin::CpuCount),
+            "inputsecret" => Some(Builtin::InputSecret),
+            "readprompt" => Some(Builtin::ReadPrompt),
+            "stylize" => Some(Builtin::Stylize),
+            "redln" => Some(Builtin::RedLn),
+            "greenln" => Some(Builtin::GreenLn),
+            "yellowln" => Some(Builtin::YellowLn),
+            "memstats" => Some(Builtin::MemStats),
+            "perfcounter" => Some(Builtin::PerfCounter),
+            "measure" => Some(Builtin::Measure),
+            "sum" => Some(Builtin::Sum),
+            "mean" => Some(Builtin::Mean),
+            "median" => Some(Builtin::Median),
+            "minof" => Some(Builtin::MinOf),
+            "maxof" => Some(Builtin::MaxOf),
+            "stddev" => Some(Builtin::StdDev),
+            "kvopen" => Some(Builtin::KvOpen),
+            "kvget" => Some(Builtin::KvGet),
+            "kvset" => Some(Builtin::KvSet),
+            "kvdel" => Some(Builtin::KvDel),
+            "kvhas" => Some(Builtin::KvHas),
+            "isnan" => Some(Builtin::IsNan),
+            "isinf" => Some(Builtin::IsInf),
+            "isfinite" => Some(Builtin::IsFinite),
+            "call" => Some(Builtin::Call),
+            "sqrt" => Some(Builtin::Sqrt),
+            "sin" => Some(Builtin::Sin),
+            "cos" => Some(Builtin::Cos),
+            "tan" => Some(Builtin::Tan),
+            "log" => Some(Builtin::Log),
+            "exp" => Some(Builtin::Exp),
+            "random" => Some(Builtin::Random),
+            "randint" => Some(Builtin::RandInt),
+            "time" => Some(Builtin::Time),
+            "clock" => Some(Builtin::Clock),
+            "strftime" => Some(Builtin::StrFTime),
+            "argc" => Some(Builtin::Argc),
+            "argv" => Some(Builtin::Argv),
+            "exists" => Some(Builtin::Exists),
+            "isdir" => Some(Builtin::IsDir),
+            "remove" => Some(Builtin::Remove),
+            "mkdir" => Some(Builtin::MkDir),
+            "listdir" => Some(Builtin::ListDir),
+            "depth" => Some(Builtin::Depth),
+            "sort" => Some(Builtin::Sort),
+            "sortby" => Some(Builtin::SortBy),
+            "format" => Some(Builtin::Format),
+            _ => None,
+        }
+    }
+
+    // how many elements the builtin takes from and pushes onto the stack,
+    // or None when it can't be known (e.g. `exit` never returns)
+    pub fn effect(&self) -> Option<(usize, usize)> {
+        match *self {
+            Builtin::Print | Builtin::Println | Builtin::EPrintln | Builtin::EPrint => Some((1, 0)),
+            Builtin::Read | Builtin::Readln => Some((0, 1)),
+            Builtin::ToNumber | Builtin::ToString => Some((1, 1)),
+            Builtin::Exit => None,
+            Builtin::Cmd => Some((1, 3)),
+            Builtin::CopyFile => Some((2, 0)),
+            Builtin::MoveFile => Some((2, 0)),
+            Builtin::Symlink => Some((2, 0)),
+            Builtin::ReadLink => Some((1, 1)),
+            Builtin::WatchPath => Some((1, 1)),
+            Builtin::Platform => Some((0, 1)),
+            Builtin::Hostname => Some((0, 1)),
+            Builtin::Username => Some((0, 1)),
+            Builtin::CpuCount => Some((0, 1)),
+            Builtin::InputSecret => Some((0, 1)),
+            Builtin::ReadPrompt => Some((1, 1)),
+            Builtin::Stylize => Some((2, 1)),
+            Builtin::RedLn => Some((1, 0)),
+            Builtin::GreenLn => Some((1, 0)),
+            Builtin::YellowLn => Some((1, 0)),
+            Builtin::MemStats => Some((0, 2)),
+            Builtin::PerfCounter => Some((0, 1)),
+            Builtin::Measure => None,
+            Builtin::Sum => None,
+            Builtin::Mean => None,
+            Builtin::Median => None,
+            Builtin::MinOf => None,
+            Builtin::MaxOf => None,
+            Builtin::StdDev => None,
+            Builtin::KvOpen => Some((1, 0)),
+            Builtin::KvGet => Some((1, 1)),
+            Builtin::KvSet => Some((2, 0)),
+            Builtin::KvDel => Some((1, 0)),
+            Builtin::KvHas => Some((1, 1)),
+            Builtin::IsNan => Some((1, 1)),
+            Builtin::IsInf => Some((1, 1)),
+            Builtin::IsFinite => Some((1, 1)),
+            Builtin::Call => None,
+            Builtin::Sqrt => Some((1, 1)),
+            Builtin::Sin => Some((1, 1)),
+            Builtin::Cos => Some((1, 1)),
+            Builtin::Tan => Some((1, 1)),
+            Builtin::Log => Some((1, 1)),
+            Builtin::Exp => Some((1, 1)),
+            Builtin::Random => Some((0, 1)),
+            Builtin::RandInt => Some((2, 1)),
+            Builtin::Time => Some((0, 1)),
+            Builtin::Clock => Some((0, 1)),
+            Builtin::StrFTime => Some((2, 1)),
+            Builtin::Argc => Some((0, 1)),
+            Builtin::Argv => Some((1, 1)),
+            Builtin::Exists => Some((1, 1)),
+            Builtin::IsDir => Some((1, 1)),
+            Builtin::Remove => Some((1, 0)),
+            Builtin::MkDir => Some((1, 0)),
+            Builtin::ListDir => None,
+            Builtin::Depth => Some((0, 1)),
+            Builtin::Sort => Some((1, 1)),
+            Builtin::SortBy => Some((2, 1)),
+            Builtin::Format => None,
         }
     }
 }
 
+// Constants that can be used without defining them. Unlike builtins, a definition or
+// procedure with the same name takes their place (e.g. an older `def PI 3.14 end`).
+pub fn constant(word: &str) -> Option<f64> {
+    match word {
+        "PI" => Some(std::f64::consts::PI),
+        "E" => Some(std::f64::consts::E),
+        _ => None,
+    }
+}
+
 pub type Stack = VecDeque<Data>;
 
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1000;
+
+// How many blocks (the bodies of calls, `if`, loops, `try`...) can be nested in each other
+// for each call allowed by the call depth.
+const LEVELS_PER_CALL: usize = 4;
+
+// Stack the interpreter needs for `depth` nested calls. Each nested block is a few
+// recursive Rust calls, and unoptimized builds use much bigger frames than release ones
+// (`call` takes the most, about 72K and 3K).
+pub fn stack_size(depth: usize) -> usize {
+    let per_level = if cfg!(debug_assertions) {
+        48 << 10
+    } else {
+        4 << 10
+    };
+    (16 << 20) + depth.saturating_mul(LEVELS_PER_CALL * per_level)
+}
+
+// Modification times of `path` and, if it's a directory, of everything inside it.
+fn modification_times(path: &Path) -> std::io::Result<HashMap<PathBuf, SystemTime>> {
+    let mut times = HashMap::new();
+    let meta = std::fs::symlink_metadata(path)?;
+    times.insert(path.to_path_buf(), meta.modified()?);
+    if meta.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            times.extend(modification_times(&entry?.path())?);
+        }
+    }
+    Ok(times)
+}
+
+// Name of the machine, or an empty string if it can't be found out.
+#[cfg(unix)]
+fn hostname() -> String {
+    extern "C" {
+        fn gethostname(name: *mut u8, len: usize) -> i32;
+    }
+    let mut buf = [0u8; 256];
+    if unsafe { gethostname(buf.as_mut_ptr(), buf.len()) } != 0 {
+        return String::new();
+    }
+    let end = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..end]).to_string()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+// Different for every run: the time mixed with the process id. Never zero, which would
+// keep xorshift at zero forever.
+fn random_seed() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+    (nanos ^ ((std::process::id() as u64) << 32)) | 1
+}
+
+const WEEKDAYS: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+// Formats the unix time `t` (in UTC) like C's strftime. Returns the letter of the first
+// unsupported `%` directive as the error.
+fn strftime(format: &str, t: f64) -> Result<String, char> {
+    let secs = t.floor() as i64;
+    let days = secs.div_euclid(86400);
+    let rem = secs.rem_euclid(86400);
+    // days since 1970-01-01 to a civil date (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let before = [0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334][month as usize - 1];
+    let yday = before + day + (leap && month > 2) as i64;
+    let weekday = (days + 4).rem_euclid(7) as usize; // 1970-01-01 was a Thursday
+
+    let mut out = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => out.push_str(&year.to_string()),
+            Some('y') => out.push_str(&format!("{:02}", year.rem_euclid(100))),
+            Some('m') => out.push_str(&format!("{:02}", month)),
+            Some('d') => out.push_str(&format!("{:02}", day)),
+            Some('j') => out.push_str(&format!("{:03}", yday)),
+            Some('H') => out.push_str(&format!("{:02}", rem / 3600)),
+            Some('M') => out.push_str(&format!("{:02}", rem / 60 % 60)),
+            Some('S') => out.push_str(&format!("{:02}", rem % 60)),
+            Some('s') => out.push_str(&secs.to_string()),
+            Some('A') => out.push_str(WEEKDAYS[weekday]),
+            Some('a') => out.push_str(&WEEKDAYS[weekday][..3]),
+            Some('B') => out.push_str(MONTHS[month as usize - 1]),
+            Some('b') => out.push_str(&MONTHS[month as usize - 1][..3]),
+            Some('%') => out.push('%'),
+            Some(d) => return Err(d),
+            None => return Err('%'),
+        }
+    }
+    Ok(out)
+}
+
+// Splits a `format` template into text (Some) and `{}` placeholders (None).
+fn format_pieces(template: &str) -> Vec<Option<String>> {
+    let mut pieces = Vec::new();
+    let mut text = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                text.push(c);
+            }
+            ('{', Some('}')) => {
+                chars.next();
+                pieces.push(Some(std::mem::take(&mut text)));
+                pieces.push(None);
+            }
+            _ => text.push(c),
+        }
+    }
+    pieces.push(Some(text));
+    pieces
+}
+
+// Key-value stores (see `kvopen`) are text files with one entry per line: the key, a tab,
+// `n` or `s` for the type of the value, another tab and the value. Tabs, newlines and
+// backslashes inside keys and strings are escaped.
+fn kv_escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn kv_unescape(s: &str) -> String {
+    let mut result = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        result.push(match (c, chars.clone().next()) {
+            ('\\', Some(e @ ('\\' | 't' | 'n' | 'r'))) => {
+                chars.next();
+                match e {
+                    't' => '\t',
+                    'n' => '\n',
+                    'r' => '\r',
+                    _ => '\\',
+                }
+            }
+            _ => c,
+        });
+    }
+    result
+}
+
+fn kv_format(entries: &BTreeMap<String, Data>) -> String {
+    let mut out = String::new();
+    for (k, v) in entries {
+        let (kind, value) = match v {
+            Data::Number(n) => ("n", n.to_string()),
+            Data::String(s) => ("s", kv_escape(s)),
+            // `kvset` refuses quotations, so there are none to write
+            Data::Quote(_) => continue,
+        };
+        out.push_str(&format!("{}\t{}\t{}\n", kv_escape(k), kind, value));
+    }
+    out
+}
+
+fn kv_parse(source: &str) -> Result<BTreeMap<String, Data>, String> {
+    let mut entries = BTreeMap::new();
+    for (i, line) in source.lines().enumerate() {
+        let mut fields = line.splitn(3, '\t');
+        let value = match (fields.next(), fields.next(), fields.next()) {
+            (Some(k), Some("n"), Some(v)) => v.parse().ok().map(|n| (k, Data::Number(n))),
+            (Some(k), Some("s"), Some(v)) => Some((k, Data::String(kv_unescape(v)))),
+            _ => None,
+        };
+        match value {
+            Some((k, v)) => entries.insert(kv_unescape(k), v),
+            None => return Err(format!("the store is invalid at line {}", i + 1)),
+        };
+    }
+    Ok(entries)
+}
+
+// ANSI code of a text attribute accepted by `stylize`.
+fn style_code(attr: &str) -> Option<u8> {
+    let colors = [
+        "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+    ];
+    match attr {
+        "bold" => Some(1),
+        "dim" => Some(2),
+        "italic" => Some(3),
+        "underline" => Some(4),
+        _ => colors.iter().position(|&c| c == attr).map(|i| 30 + i as u8),
+    }
+}
+
+// Runs `stty` on the terminal attached to stdin, returning what it printed.
+#[cfg(unix)]
+fn stty(args: &[&str]) -> Option<String> {
+    let out = std::process::Command::new("stty")
+        .args(args)
+        .stdin(std::process::Stdio::inherit())
+        .output()
+        .ok()?;
+    out.status
+        .success()
+        .then(|| String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+#[cfg(not(unix))]
+fn stty(_: &[&str]) -> Option<String> {
+    None
+}
+
+// Turns the terminal's echo on or off, returning whether it worked.
+fn set_echo(on: bool) -> bool {
+    stty(&[if on { "echo" } else { "-echo" }]).is_some()
+}
+
+// Reads a line from a terminal in raw mode, with the usual editing keys (arrows, Home/End,
+// Backspace, Delete, Ctrl+A/E/U) and Up/Down to go through `history`. Returns `None` on
+// Ctrl+D with an empty line, and sets `INTERRUPTED` on Ctrl+C.
+fn edit_line(
+    input: &mut dyn BufRead,
+    output: &mut dyn Write,
+    prompt: &str,
+    history: &[String],
+) -> std::io::Result<Option<String>> {
+    let mut line: Vec<char> = Vec::new();
+    let mut cursor = 0;
+    let mut entry = history.len(); // history entry being shown (len = the new line)
+    let mut draft: Vec<char> = Vec::new(); // what was typed before going through the history
+    let mut byte = [0u8; 1];
+    loop {
+        write!(
+            output,
+            "\r{}{}\x1b[K",
+            prompt,
+            line.iter().collect::<String>()
+        )?;
+        if cursor < line.len() {
+            write!(output, "\x1b[{}D", line.len() - cursor)?;
+        }
+        output.flush()?;
+        if input.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        match byte[0] {
+            b'\r' | b'\n' => break,
+            3 => {
+                INTERRUPTED.store(true, Ordering::Relaxed);
+                break;
+            }
+            4 if line.is_empty() => {
+                write!(output, "\r\n")?;
+                return Ok(None);
+            }
+            1 => cursor = 0,
+            5 => cursor = line.len(),
+            21 => {
+                line.drain(..cursor);
+                cursor = 0;
+            }
+            8 | 127 if cursor > 0 => {
+                cursor -= 1;
+                line.remove(cursor);
+            }
+            27 => {
+                // escape sequences: ESC [ <key> or ESC [ <n> ~
+                let mut seq = [0u8; 2];
+                input.read_exact(&mut seq)?;
+                if seq[1].is_ascii_digit() {
+                    input.read_exact(&mut byte)?;
+                }
+                match seq[1] {
+                    b'A' if entry > 0 => {
+                        if entry == history.len() {
+                            draft = line.clone();
+                        }
+                        entry -= 1;
+                        line = history[entry].chars().collect();
+                        cursor = line.len();
+                    }
+                    b'B' if entry < history.len() => {
+                        entry += 1;
+                        line = match history.get(entry) {
+                            Some(h) => h.chars().collect(),
+                            None => draft.clone(),
+                        };
+                        cursor = line.len();
+                    }
+                    b'C' if cursor < line.len() => cursor += 1,
+                    b'D' if cursor > 0 => cursor -= 1,
+                    b'H' | b'1' => cursor = 0,
+                    b'F' | b'4' => cursor = line.len(),
+                    b'3' if cursor < line.len() => {
+                        line.remove(cursor);
+                    }
+                    _ => {}
+                }
+            }
+            b if b >= 0x20 && b != 127 => {
+                // collect the rest of a multi-byte UTF-8 character
+                let mut bytes = vec![b];
+                let len = match b {
+                    0xf0.. => 4,
+                    0xe0.. => 3,
+                    0xc0.. => 2,
+                    _ => 1,
+                };
+                bytes.resize(len, 0);
+                input.read_exact(&mut bytes[1..])?;
+                for c in String::from_utf8_lossy(&bytes).chars() {
+                    line.insert(cursor, c);
+                    cursor += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    write!(output, "\r\n")?;
+    Ok(Some(line.into_iter().collect()))
+}
+
+// set by the SIGINT handler, checked by the runtime between nodes
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Makes Ctrl+C stop the program with an `Interrupted` error (which shows where it was)
+// instead of killing the process. Pressing Ctrl+C again kills it right away, in case the
+// program is blocked waiting for input.
+#[cfg(unix)]
+pub fn catch_interrupts() {
+    const SIGINT: i32 = 2;
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+        fn _exit(status: i32) -> !;
+    }
+    extern "C" fn handler(_: i32) {
+        if INTERRUPTED.swap(true, Ordering::Relaxed) {
+            unsafe { _exit(130) }
+        }
+    }
+    unsafe {
+        signal(SIGINT, handler);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn catch_interrupts() {}
+
+// Turns a failed write to the program's output (a closed pipe, a full disk, a writer
+// given by the host that gives up) into an `IOError` instead of a panic.
+fn write_error<'s>(
+    span: &'s TokenSpan,
+    op: &'s impl std::fmt::Display,
+) -> impl FnOnce(std::io::Error) -> RuntimeError + 's {
+    move |e| RuntimeError::IOError(span.clone(), op.to_string(), e.to_string())
+}
+
+// bytes on the heap right now, kept up to date by `CountingAllocator`
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+// The system allocator, counting how much memory is in use so that `--max-memory` can
+// stop a program that takes too much. It only counts when it's the global allocator
+// (the `pile` binary makes it so), embedders that want the limit have to do the same:
+// `#[global_allocator] static A: CountingAllocator = CountingAllocator;`
+pub struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let p = unsafe { System.alloc(layout) };
+        if !p.is_null() {
+            ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        p
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let p = unsafe { System.alloc_zeroed(layout) };
+        if !p.is_null() {
+            ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        p
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let p = unsafe { System.realloc(ptr, layout, new_size) };
+        if !p.is_null() {
+            ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+            ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        }
+        p
+    }
+}
+
+// elements preallocated for the stack (see `Runtime::reserve_stack` to grow it upfront)
+pub const STACK_CAPACITY: usize = 1024;
+
 #[derive(Debug)]
 pub enum RuntimeError {
     ProcedureError {
@@ -149,42 +1011,179 @@ pub enum RuntimeError {
     ProcRedefinition(TokenSpan, String),           // used when a procedure name is already taken
     DefRedefinition(TokenSpan, String),            // used when a definition name is already taken
     EmptyDefinition(TokenSpan, String),            // used when a definition has empty body
+    HostError(TokenSpan, String, String), // used when a host-registered word reports a failure
+    IOError(TokenSpan, String, String),   // used when a filesystem or I/O operation fails
+    Timeout(TokenSpan, f64),              // used when the program runs for longer than allowed
+    Interrupted(TokenSpan, Option<String>), // used when the user presses Ctrl+C (holds the top of the stack)
+    StackEffectMismatch(TokenSpan, String, String, usize, isize), // used when a procedure doesn't follow its declared stack effect
+    CallStackOverflow(TokenSpan, usize), // used when procedures and quotations are nested deeper than allowed
+    InstructionLimit(TokenSpan, usize),  // used when the program runs more steps than allowed
+    MemoryLimit(TokenSpan, usize),       // used when the program takes more memory than allowed
+}
+
+impl RuntimeError {
+    // Ctrl+C and the limits on time, steps and memory end the program even inside `try`
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            RuntimeError::ProcedureError { inner, .. } => inner.is_fatal(),
+            RuntimeError::Timeout(..)
+            | RuntimeError::Interrupted(..)
+            | RuntimeError::InstructionLimit(..)
+            | RuntimeError::MemoryLimit(..) => true,
+            _ => false,
+        }
+    }
+
+    // where the error happened (for errors inside procedures, the outermost call)
+    pub fn span(&self) -> &TokenSpan {
+        match self {
+            RuntimeError::ProcedureError { call: s, .. }
+            | RuntimeError::StackUnderflow(s, _, _)
+            | RuntimeError::UnexpectedType(s, _, _, _)
+            | RuntimeError::InvalidWord(s, _)
+            | RuntimeError::ValueError(s, _, _, _)
+            | RuntimeError::ProcRedefinition(s, _)
+            | RuntimeError::DefRedefinition(s, _)
+            | RuntimeError::EmptyDefinition(s, _)
+            | RuntimeError::HostError(s, _, _)
+            | RuntimeError::IOError(s, _, _)
+            | RuntimeError::Timeout(s, _)
+            | RuntimeError::Interrupted(s, _)
+            | RuntimeError::StackEffectMismatch(s, _, _, _, _)
+            | RuntimeError::CallStackOverflow(s, _)
+            | RuntimeError::InstructionLimit(s, _)
+            | RuntimeError::MemoryLimit(s, _) => s,
+        }
+    }
 }
 
 pub struct Runtime<'a> {
     input: &'a ProgramTree,
+    stdin: Box<dyn BufRead + 'a>,
+    stdout: Box<dyn Write + 'a>,
+    stderr: Box<dyn Write + 'a>,
     stack: Stack,
     namespace: Namespace<'a>,
     stop: bool,
+    loops: usize, // loops being executed, `stop` in a procedure called from one ends it
+    scanned: bool,
+    counters: Vec<(String, f64)>, // counters of the `times` and `for` loops being executed (innermost last)
+    deadline: Option<(Instant, f64)>, // when to interrupt the program and the timeout in seconds
+    steps: usize,                 // nodes run so far
+    history: Vec<String>,         // lines entered with `readprompt`
+    colors: bool,                 // whether `stylize` and friends emit escape codes
+    terminal: bool,               // whether `stdin` is a terminal (for `inputsecret`, `readprompt`)
+    warnings: bool,               // whether the checker's warnings are shown
+    entry: Option<String>,        // procedure called after the top-level code (--entry)
+    epoch: Instant,               // what `perfcounter` counts from
+    store: Option<(PathBuf, BTreeMap<String, Data>)>, // key-value store opened with `kvopen`
+    debug: bool,                  // whether `debug` prints anything (--no-debug)
+    rng: u64,                     // state of the generator behind `random` and `randint`
+    args: Vec<String>,            // command-line arguments given to the program (`argc`/`argv`)
+    calls: usize,                 // procedures and quotations being run right now
+    levels: usize,                // blocks being run right now, nested in each other
+    max_calls: usize,             // how deep they can be nested (--max-call-depth)
+    tracing: bool,                // whether every node is printed before it runs (--trace)
+    max_steps: Option<usize>,     // how many steps the program can run (--max-instructions)
+    max_memory: Option<(usize, usize)>, // heap in use when set and the limit (--max-memory)
 }
 
 impl<'a> Runtime<'a> {
     pub fn new(input: &'a ProgramTree) -> Self {
+        let mut r = Self::with_streams(
+            input,
+            Box::new(std::io::stdin().lock()),
+            Box::new(std::io::stdout()),
+            Box::new(std::io::stderr()),
+        );
+        // https://no-color.org
+        r.colors = std::io::stdout().is_terminal()
+            && std::env::var("NO_COLOR").map_or(true, |v| v.is_empty());
+        // streams given to `with_streams` are never treated as the terminal
+        r.terminal = std::io::stdin().is_terminal();
+        r
+    }
+
+    // Creates a runtime that reads and writes through the given streams instead of the
+    // process' standard streams (useful for capturing output or feeding scripted input).
+    pub fn with_streams(
+        input: &'a ProgramTree,
+        stdin: Box<dyn BufRead + 'a>,
+        stdout: Box<dyn Write + 'a>,
+        stderr: Box<dyn Write + 'a>,
+    ) -> Self {
+        let procs = input.iter().filter(|n| matches!(n, Node::Proc(..))).count();
+        let defs = input.iter().filter(|n| matches!(n, Node::Def(..))).count();
         Self {
             input,
-            stack: VecDeque::new(),
+            stdin,
+            stdout,
+            stderr,
+            stack: VecDeque::with_capacity(STACK_CAPACITY),
             namespace: Namespace {
-                procs: Vec::new(),
-                defs: Vec::new(),
+                procs: Vec::with_capacity(procs),
+                defs: Vec::with_capacity(defs),
+                hosts: Vec::new(),
             },
             stop: false,
+            loops: 0,
+            scanned: false,
+            counters: Vec::new(),
+            deadline: None,
+            steps: 0,
+            history: Vec::new(),
+            colors: false,
+            terminal: false,
+            warnings: true,
+            entry: None,
+            epoch: Instant::now(),
+            store: None,
+            debug: true,
+            rng: random_seed(),
+            args: Vec::new(),
+            calls: 0,
+            max_calls: DEFAULT_MAX_CALL_DEPTH,
+            levels: 0,
+            tracing: false,
+            max_steps: None,
+            max_memory: None,
         }
     }
 
+    // Registers a Rust closure as a Pile word. The closure receives the runtime itself,
+    // so it can pop its arguments and push its results with `pop`, `push_number` and
+    // `push_string`. Returning `Err` aborts the program with the given message.
+    pub fn register<F>(&mut self, name: &str, f: F)
+    where
+        F: Fn(&mut Runtime<'a>) -> Result<(), String> + 'a,
+    {
+        self.namespace.hosts.retain(|h| h.0 != name);
+        self.namespace
+            .hosts
+            .push(HostProcedure(name.to_string(), Rc::new(f)));
+    }
+
     fn pre_execution_scan(&mut self) -> Result<(), RuntimeError> {
+        if self.scanned {
+            return Ok(());
+        }
+        self.scanned = true;
+        let hosts = self.namespace.hosts.iter().map(|h| h.0.clone()).collect();
+        // the checker also rejects procedures and definitions that reuse a name
+        for w in Checker::new(self.input, hosts).check()? {
+            if self.warnings {
+                warning(w);
+            }
+        }
         for n in self.input {
             match n {
-                Node::Proc(n, p, s) => {
-                    if let Some(_) = self.namespace.procs.iter().find(|p| p.0 == *n) {
-                        return Err(RuntimeError::ProcRedefinition(s.clone(), n.to_string()));
-                    }
-                    self.namespace.procs.push(Procedure(n.to_string(), p));
+                Node::Proc(n, e, p, _) => {
+                    self.namespace
+                        .procs
+                        .push(Procedure(n.to_string(), p, e.as_ref()));
                 }
                 Node::Def(n, p, s) => {
-                    if let Some(_) = self.namespace.defs.iter().find(|p| p.0 == *n) {
-                        return Err(RuntimeError::DefRedefinition(s.clone(), n.to_string()));
-                    }
-                    self.run_block(p)?;
+                    self.run_block(s, p)?;
                     if let Some(result) = self.pop() {
                         self.namespace.defs.push(Definition(n.to_string(), result));
                     } else {
@@ -197,35 +1196,55 @@ impl<'a> Runtime<'a> {
         Ok(())
     }
 
-    fn builtin(&mut self, span: TokenSpan, x: Builtin) -> Result<(), RuntimeError> {
+    fn builtin(&mut self, span: &TokenSpan, x: Builtin) -> Result<(), RuntimeError> {
         match x {
             Builtin::Println => {
                 if let Some(a) = self.pop() {
                     match a {
                         Data::String(s) => {
-                            println!("{}", s);
+                            writeln!(self.stdout, "{}", s).map_err(write_error(span, &x))?;
                         }
                         Data::Number(n) => {
-                            println!("{}", n);
+                            writeln!(self.stdout, "{}", n).map_err(write_error(span, &x))?;
+                        }
+                        Data::Quote(_) => {
+                            return Err(RuntimeError::UnexpectedType(
+                                span.clone(),
+                                format!("{}", x),
+                                "number or string".to_string(),
+                                "quote".to_string(),
+                            ))
                         }
                     }
                 } else {
-                    return Err(RuntimeError::StackUnderflow(span, "println".to_string(), 1));
+                    return Err(RuntimeError::StackUnderflow(
+                        span.clone(),
+                        "println".to_string(),
+                        1,
+                    ));
                 }
             }
             Builtin::EPrintln => {
                 if let Some(a) = self.pop() {
                     match a {
                         Data::String(s) => {
-                            eprintln!("{}", s);
+                            writeln!(self.stderr, "{}", s).map_err(write_error(span, &x))?;
                         }
                         Data::Number(n) => {
-                            eprintln!("{}", n);
+                            writeln!(self.stderr, "{}", n).map_err(write_error(span, &x))?;
+                        }
+                        Data::Quote(_) => {
+                            return Err(RuntimeError::UnexpectedType(
+                                span.clone(),
+                                format!("{}", x),
+                                "number or string".to_string(),
+                                "quote".to_string(),
+                            ))
                         }
                     }
                 } else {
                     return Err(RuntimeError::StackUnderflow(
-                        span,
+                        span.clone(),
                         "eprintln".to_string(),
                         1,
                     ));
@@ -235,37 +1254,61 @@ impl<'a> Runtime<'a> {
                 if let Some(a) = self.pop() {
                     match a {
                         Data::String(s) => {
-                            eprint!("{}", s);
-                            std::io::stderr().flush().unwrap();
+                            write!(self.stderr, "{}", s).map_err(write_error(span, &x))?;
+                            self.stderr.flush().map_err(write_error(span, &x))?;
                         }
                         Data::Number(n) => {
-                            eprint!("{}", n);
-                            std::io::stderr().flush().unwrap();
+                            write!(self.stderr, "{}", n).map_err(write_error(span, &x))?;
+                            self.stderr.flush().map_err(write_error(span, &x))?;
+                        }
+                        Data::Quote(_) => {
+                            return Err(RuntimeError::UnexpectedType(
+                                span.clone(),
+                                format!("{}", x),
+                                "number or string".to_string(),
+                                "quote".to_string(),
+                            ))
                         }
                     }
                 } else {
-                    return Err(RuntimeError::StackUnderflow(span, "eprint".to_string(), 1));
+                    return Err(RuntimeError::StackUnderflow(
+                        span.clone(),
+                        "eprint".to_string(),
+                        1,
+                    ));
                 }
             }
             Builtin::Print => {
                 if let Some(a) = self.pop() {
                     match a {
                         Data::String(s) => {
-                            print!("{}", s);
-                            std::io::stdout().flush().unwrap();
+                            write!(self.stdout, "{}", s).map_err(write_error(span, &x))?;
+                            self.stdout.flush().map_err(write_error(span, &x))?;
                         }
                         Data::Number(n) => {
-                            print!("{}", n);
-                            std::io::stdout().flush().unwrap();
+                            write!(self.stdout, "{}", n).map_err(write_error(span, &x))?;
+                            self.stdout.flush().map_err(write_error(span, &x))?;
+                        }
+                        Data::Quote(_) => {
+                            return Err(RuntimeError::UnexpectedType(
+                                span.clone(),
+                                format!("{}", x),
+                                "number or string".to_string(),
+                                "quote".to_string(),
+                            ))
                         }
                     }
                 } else {
-                    return Err(RuntimeError::StackUnderflow(span, "print".to_string(), 1));
+                    return Err(RuntimeError::StackUnderflow(
+                        span.clone(),
+                        "print".to_string(),
+                        1,
+                    ));
                 }
             }
             Builtin::Readln => {
                 let mut xs = String::new();
-                if let Ok(_) = std::io::stdin().read_line(&mut xs) {
+                if self.stdin.read_line(&mut xs).is_ok() {
                     self.push_string(xs.trim().to_string());
                 } else {
                     self.push_number(-1.0);
@@ -273,7 +1316,7 @@ impl<'a> Runtime<'a> {
             }
             Builtin::Read => {
                 let mut xs = String::new();
-                if let Ok(_) = std::io::stdin().read_to_string(&mut xs) {
+                if self.stdin.read_to_string(&mut xs).is_ok() {
                     self.push_string(xs);
                 } else {
                     self.push_number(-1.0);
@@ -283,11 +1326,12 @@ impl<'a> Runtime<'a> {
                 if let Some(a) = self.pop() {
                     match a {
                         Data::Number(n) => {
+                            self.flush().map_err(write_error(span, &x))?;
                             std::process::exit(n as i32);
                         }
                         _ => {
                             return Err(RuntimeError::UnexpectedType(
-                                span,
+                                span.clone(),
                                 "exit".to_string(),
                                 "number".to_string(),
                                 format!("{}", a),
@@ -295,6 +1339,7 @@ impl<'a> Runtime<'a> {
                         }
                     }
                 } else {
+                    self.flush().map_err(write_error(span, &x))?;
                     std::process::exit(0);
                 }
             }
@@ -305,7 +1350,7 @@ impl<'a> Runtime<'a> {
                             Ok(n) => self.push_number(n),
                             Err(_) => {
                                 return Err(RuntimeError::ValueError(
-                                    span,
+                                    span.clone(),
                                     format!("{}", x),
                                     "number".to_string(),
                                     s,
@@ -314,7 +1359,7 @@ impl<'a> Runtime<'a> {
                         },
                         a => {
                             return Err(RuntimeError::UnexpectedType(
-                                span,
+                                span.clone(),
                                 format!("{}", x),
                                 "numbers or strings".to_string(),
                                 format!("({})", &a),
@@ -322,7 +1367,11 @@ impl<'a> Runtime<'a> {
                         }
                     }
                 } else {
-                    return Err(RuntimeError::StackUnderflow(span, format!("{}", x), 1));
+                    return Err(RuntimeError::StackUnderflow(
+                        span.clone(),
+                        format!("{}", x),
+                        1,
+                    ));
                 }
             }
             Builtin::ToString => {
@@ -330,60 +1379,715 @@ impl<'a> Runtime<'a> {
                     match a {
                         Data::Number(n) => self.push_string(n.to_string()),
                         Data::String(s) => self.push_string(s),
+                        Data::Quote(_) => {
+                            return Err(RuntimeError::UnexpectedType(
+                                span.clone(),
+                                format!("{}", x),
+                                "number or string".to_string(),
+                                "quote".to_string(),
+                            ))
+                        }
                     }
                 } else {
-                    return Err(RuntimeError::StackUnderflow(span, format!("{}", x), 1));
+                    return Err(RuntimeError::StackUnderflow(
+                        span.clone(),
+                        format!("{}", x),
+                        1,
+                    ));
                 }
             }
-        }
-        Ok(())
-    }
-
-    fn unop(&mut self, span: TokenSpan, x: UnaryOp) -> Result<(), RuntimeError> {
-        if let Some(a) = self.pop() {
-            match a {
-                Data::Number(n) => match x {
-                    UnaryOp::Trace => println!("number {}", n),
-                    UnaryOp::Dup => {
-                        self.push_number(n);
-                        self.push_number(n);
+            Builtin::Cmd => {
+                // pushes the command's stdout, stderr and exit code (on top)
+                let c = self.pop_string(span, &x)?;
+                let output = if cfg!(windows) {
+                    std::process::Command::new("cmd").args(["/C", &c]).output()
+                } else {
+                    std::process::Command::new("sh").args(["-c", &c]).output()
+                };
+                match output {
+                    Ok(o) => {
+                        self.push_string(String::from_utf8_lossy(&o.stdout).to_string());
+                        self.push_string(String::from_utf8_lossy(&o.stderr).to_string());
+                        self.push_number(o.status.code().unwrap_or(-1) as f64);
                     }
-                    UnaryOp::Drop => {},
-                    UnaryOp::BNot => self.push_number(!(n as i32) as f64),
-                },
-                Data::String(s) => match x {
-                    UnaryOp::Trace => println!("string \"{}\"", s),
-                    UnaryOp::Dup => {
-                        self.push_string(s.clone());
-                        self.push_string(s);
+                    Err(e) => {
+                        self.push_string(String::new());
+                        self.push_string(e.to_string());
+                        self.push_number(-1.0);
                     }
-                    UnaryOp::Drop => {},
-                    UnaryOp::BNot => {
-                        return Err(RuntimeError::UnexpectedType(
-                            span,
-                            format!("{}", x),
-                            "number".to_string(),
-                            "string".to_string(),
-                        ))
-                    },
-                },
+                }
             }
-        } else {
-            return Err(RuntimeError::StackUnderflow(span, format!("{}", x), 1));
-        }
-        Ok(())
-    }
-
-    fn binop(&mut self, span: TokenSpan, x: BinaryOp) -> Result<(), RuntimeError> {
-        if let (Some(a), Some(b)) = (self.pop(), self.pop()) {
-            match (a, b) {
-                (Data::Number(n1), Data::Number(n2)) => match x {
-                    BinaryOp::Add => self.push_number(n1 + n2),
-                    BinaryOp::Sub => self.push_number(n1 - n2),
-                    BinaryOp::Mul => self.push_number(n1 * n2),
-                    BinaryOp::Div => self.push_number(n1 / n2),
-                    BinaryOp::Mod => self.push_number(n1 % n2),
-                    BinaryOp::Exp => self.push_number(n1.powf(n2)),
+            Builtin::CopyFile => {
+                let to = self.pop_string(span, &x)?;
+                let from = self.pop_string(span, &x)?;
+                if let Err(e) = std::fs::copy(&from, &to) {
+                    return Err(RuntimeError::IOError(
+                        span.clone(),
+                        format!("{}", x),
+                        e.to_string(),
+                    ));
+                }
+            }
+            Builtin::MoveFile => {
+                let to = self.pop_string(span, &x)?;
+                let from = self.pop_string(span, &x)?;
+                if let Err(e) = std::fs::rename(&from, &to) {
+                    return Err(RuntimeError::IOError(
+                        span.clone(),
+                        format!("{}", x),
+                        e.to_string(),
+                    ));
+                }
+            }
+            Builtin::Symlink => {
+                // same order as `ln -s`: the target goes first, then the link to create
+                let link = self.pop_string(span, &x)?;
+                let target = self.pop_string(span, &x)?;
+                #[cfg(unix)]
+                let result = std::os::unix::fs::symlink(&target, &link);
+                #[cfg(windows)]
+                let result = std::os::windows::fs::symlink_file(&target, &link);
+                if let Err(e) = result {
+                    return Err(RuntimeError::IOError(
+                        span.clone(),
+                        format!("{}", x),
+                        e.to_string(),
+                    ));
+                }
+            }
+            Builtin::ReadLink => {
+                let path = self.pop_string(span, &x)?;
+                let target = std::fs::read_link(&path).map_err(|e| {
+                    RuntimeError::IOError(span.clone(), format!("{}", x), e.to_string())
+                })?;
+                self.push_string(target.to_string_lossy().to_string());
+            }
+            Builtin::WatchPath => {
+                // there's no portable way to get notified, so the path is polled
+                let path = self.pop_string(span, &x)?;
+                let before = modification_times(Path::new(&path)).map_err(|e| {
+                    RuntimeError::IOError(span.clone(), format!("{}", x), e.to_string())
+                })?;
+                loop {
+                    std::thread::sleep(Duration::from_millis(100));
+                    self.check_interrupted(span)?;
+                    self.check_deadline(span)?;
+                    // the path itself may be gone, which also counts as a change
+                    let after = modification_times(Path::new(&path)).unwrap_or_default();
+                    // adding or removing a file also touches its directory, so the
+                    // deepest path is the one reported
+                    let changed = after
+                        .iter()
+                        .filter(|(p, t)| before.get(*p) != Some(t))
+                        .map(|(p, _)| p)
+                        .chain(before.keys().filter(|p| !after.contains_key(*p)))
+                        .max_by_key(|p| p.components().count());
+                    if let Some(p) = changed {
+                        self.push_string(p.to_string_lossy().to_string());
+                        break;
+                    }
+                }
+            }
+            Builtin::Platform => self.push_string(std::env::consts::OS.to_string()),
+            Builtin::Hostname => self.push_string(hostname()),
+            Builtin::Username => {
+                let name = std::env::var("USER")
+                    .or_else(|_| std::env::var("LOGNAME"))
+                    .or_else(|_| std::env::var("USERNAME"))
+                    .unwrap_or_default();
+                self.push_string(name);
+            }
+            Builtin::CpuCount => {
+                let n = std::thread::available_parallelism().map_or(1, |n| n.get());
+                self.push_number(n as f64);
+            }
+            Builtin::InputSecret => {
+                // like `readln`, but what's typed isn't shown (for passwords and tokens)
+                self.stdout.flush().map_err(write_error(span, &x))?;
+                let hidden = self.terminal && set_echo(false);
+                let mut xs = String::new();
+                let result = self.stdin.read_line(&mut xs);
+                if hidden {
+                    set_echo(true);
+                    // the user's Enter wasn't echoed either
+                    writeln!(self.stdout).map_err(write_error(span, &x))?;
+                }
+                if result.is_ok() {
+                    self.push_string(xs.trim().to_string());
+                } else {
+                    self.push_number(-1.0);
+                }
+            }
+            Builtin::ReadPrompt => {
+                let prompt = self.pop_string(span, &x)?;
+                let saved = match self.terminal {
+                    true => stty(&["-g"]),
+                    false => None,
+                };
+                let line = match &saved {
+                    Some(mode) => {
+                        stty(&["raw", "-echo"]);
+                        let line =
+                            edit_line(&mut self.stdin, &mut self.stdout, &prompt, &self.history);
+                        stty(&[mode]);
+                        line.ok().flatten()
+                    }
+                    None => {
+                        // not a terminal, so there's nothing to edit
+                        write!(self.stdout, "{}", prompt).map_err(write_error(span, &x))?;
+                        self.stdout.flush().map_err(write_error(span, &x))?;
+                        let mut xs = String::new();
+                        match self.stdin.read_line(&mut xs) {
+                            Ok(0) | Err(_) => None,
+                            Ok(_) => Some(xs.trim_end_matches(['\n', '\r']).to_string()),
+                        }
+                    }
+                };
+                self.check_interrupted(span)?;
+                match line {
+                    Some(l) => {
+                        if !l.trim().is_empty() && self.history.last() != Some(&l) {
+                            self.history.push(l.clone());
+                        }
+                        self.push_string(l);
+                    }
+                    None => self.push_number(-1.0),
+                }
+            }
+            Builtin::Stylize => {
+                // the attributes are a space-separated list like "bold red"
+                let attrs = self.pop_string(span, &x)?;
+                let text = self.pop_string(span, &x)?;
+                let mut codes = Vec::new();
+                for a in attrs.split_whitespace() {
+                    match style_code(a) {
+                        Some(c) => codes.push(c),
+                        None => {
+                            return Err(RuntimeError::ValueError(
+                                span.clone(),
+                                format!("{}", x),
+                                "style".to_string(),
+                                format!("\"{}\"", a),
+                            ))
+                        }
+                    }
+                }
+                let styled = self.style(&text, &codes);
+                self.push_string(styled);
+            }
+            Builtin::RedLn | Builtin::GreenLn | Builtin::YellowLn => {
+                let text = match self.pop() {
+                    Some(Data::String(s)) => s,
+                    Some(Data::Number(n)) => n.to_string(),
+                    Some(a) => {
+                        return Err(RuntimeError::UnexpectedType(
+                            span.clone(),
+                            format!("{}", x),
+                            "number or string".to_string(),
+                            format!("{}", a),
+                        ))
+                    }
+                    None => {
+                        return Err(RuntimeError::StackUnderflow(
+                            span.clone(),
+                            format!("{}", x),
+                            1,
+                        ))
+                    }
+                };
+                let color = match x {
+                    Builtin::RedLn => "red",
+                    Builtin::GreenLn => "green",
+                    _ => "yellow",
+                };
+                let styled = self.style(&text, &[style_code(color).unwrap()]);
+                writeln!(self.stdout, "{}", styled).map_err(write_error(span, &x))?;
+            }
+            Builtin::MemStats => {
+                // strings are the only values that live on the heap: the ones on the
+                // stack and the ones kept by definitions
+                let strings = self
+                    .stack
+                    .iter()
+                    .chain(self.namespace.defs.iter().map(|d| &d.1))
+                    .filter_map(|a| match a {
+                        Data::String(s) => Some(s.capacity()),
+                        _ => None,
+                    });
+                let (count, bytes) = strings.fold((0, 0), |(c, b), s| (c + 1, b + s));
+                self.push_number(count as f64);
+                self.push_number(bytes as f64);
+            }
+            Builtin::PerfCounter => {
+                // nanoseconds since the runtime was created (only differences are meaningful)
+                let ns = self.epoch.elapsed().as_nanos();
+                self.push_number(ns as f64);
+            }
+            Builtin::Measure => {
+                // runs a quotation, or calls the procedure with the given name, and pushes
+                // how long it took
+                let start = Instant::now();
+                match self.pop() {
+                    Some(Data::Quote(q)) => self.call_quote(span, &q)?,
+                    Some(Data::String(name)) => {
+                        let Some(p) = self.namespace.procs.iter().find(|p| p.0 == name) else {
+                            return Err(RuntimeError::InvalidWord(span.clone(), name));
+                        };
+                        let (body, effect) = (p.1, p.2);
+                        self.call_procedure(span, &name, body, effect)?;
+                    }
+                    Some(a) => {
+                        return Err(RuntimeError::UnexpectedType(
+                            span.clone(),
+                            format!("{}", x),
+                            "quote or string".to_string(),
+                            format!("{}", a),
+                        ))
+                    }
+                    None => {
+                        return Err(RuntimeError::StackUnderflow(
+                            span.clone(),
+                            format!("{}", x),
+                            1,
+                        ))
+                    }
+                }
+                let ns = start.elapsed().as_nanos();
+                self.push_number(ns as f64);
+            }
+            Builtin::Sum => {
+                let xs = self.pop_counted_numbers(span, &x)?;
+                self.push_number(xs.iter().fold(0.0, |a, b| a + b));
+            }
+            Builtin::Mean | Builtin::Median | Builtin::MinOf | Builtin::MaxOf | Builtin::StdDev => {
+                let mut xs = self.pop_counted_numbers(span, &x)?;
+                if xs.is_empty() {
+                    return Err(RuntimeError::ValueError(
+                        span.clone(),
+                        format!("{}", x),
+                        "count".to_string(),
+                        "0".to_string(),
+                    ));
+                }
+                let n = xs.len() as f64;
+                let mean = xs.iter().sum::<f64>() / n;
+                let result = match x {
+                    Builtin::Mean => mean,
+                    Builtin::Median => {
+                        xs.sort_by(|a, b| a.total_cmp(b));
+                        let m = xs.len() / 2;
+                        if xs.len() % 2 == 0 {
+                            (xs[m - 1] + xs[m]) / 2.0
+                        } else {
+                            xs[m]
+                        }
+                    }
+                    Builtin::MinOf => xs.iter().copied().fold(f64::INFINITY, f64::min),
+                    Builtin::MaxOf => xs.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+                    // population standard deviation
+                    _ => (xs.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n).sqrt(),
+                };
+                self.push_number(result);
+            }
+            Builtin::KvOpen => {
+                // the file is created by the first `kvset`
+                let path = PathBuf::from(self.pop_string(span, &x)?);
+                let entries = match std::fs::read_to_string(&path) {
+                    Ok(source) => kv_parse(&source)
+                        .map_err(|e| RuntimeError::IOError(span.clone(), format!("{}", x), e))?,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+                    Err(e) => {
+                        return Err(RuntimeError::IOError(
+                            span.clone(),
+                            format!("{}", x),
+                            e.to_string(),
+                        ))
+                    }
+                };
+                self.store = Some((path, entries));
+            }
+            Builtin::KvGet | Builtin::KvHas | Builtin::KvSet | Builtin::KvDel => {
+                let value = match x {
+                    Builtin::KvSet => match self.pop() {
+                        // the store file only has room for strings and numbers
+                        Some(a @ Data::Quote(_)) => {
+                            return Err(RuntimeError::UnexpectedType(
+                                span.clone(),
+                                format!("{}", x),
+                                "string or number".to_string(),
+                                format!("{}", a),
+                            ))
+                        }
+                        Some(a) => Some(a),
+                        None => {
+                            return Err(RuntimeError::StackUnderflow(
+                                span.clone(),
+                                format!("{}", x),
+                                2,
+                            ))
+                        }
+                    },
+                    _ => None,
+                };
+                let key = self.pop_string(span, &x)?;
+                let Some((path, entries)) = &mut self.store else {
+                    return Err(RuntimeError::IOError(
+                        span.clone(),
+                        format!("{}", x),
+                        "no store is open (use `kvopen` first)".to_string(),
+                    ));
+                };
+                match x {
+                    // a missing key gives -1, like reading past the end of the input
+                    Builtin::KvGet => {
+                        let a = entries.get(&key).cloned().unwrap_or(Data::Number(-1.0));
+                        self.stack.push_front(a);
+                    }
+                    Builtin::KvHas => {
+                        let found = entries.contains_key(&key);
+                        self.push_number(found as i32 as f64);
+                    }
+                    _ => {
+                        match value {
+                            Some(a) => entries.insert(key, a),
+                            None => entries.remove(&key),
+                        };
+                        // written right away, so nothing is lost if the program fails later
+                        std::fs::write(path, kv_format(entries)).map_err(|e| {
+                            RuntimeError::IOError(span.clone(), format!("{}", x), e.to_string())
+                        })?;
+                    }
+                }
+            }
+            Builtin::IsNan | Builtin::IsInf | Builtin::IsFinite => {
+                let n = self.pop_number(span, &x)?;
+                let result = match x {
+                    Builtin::IsNan => n.is_nan(),
+                    Builtin::IsInf => n.is_infinite(),
+                    _ => n.is_finite(),
+                };
+                self.push_number(result as i32 as f64);
+            }
+            Builtin::Call => {
+                let q = match self.pop() {
+                    Some(Data::Quote(q)) => q,
+                    Some(a) => {
+                        return Err(RuntimeError::UnexpectedType(
+                            span.clone(),
+                            format!("{}", x),
+                            "quote".to_string(),
+                            format!("{}", a),
+                        ))
+                    }
+                    None => {
+                        return Err(RuntimeError::StackUnderflow(
+                            span.clone(),
+                            format!("{}", x),
+                            1,
+                        ))
+                    }
+                };
+                self.call_quote(span, &q)?;
+            }
+            Builtin::Sqrt
+            | Builtin::Sin
+            | Builtin::Cos
+            | Builtin::Tan
+            | Builtin::Log
+            | Builtin::Exp => {
+                // angles are in radians and `log` is the natural logarithm. values out of
+                // the domain give NAN, like `-1 sqrt`
+                let n = self.pop_number(span, &x)?;
+                let result = match x {
+                    Builtin::Sqrt => n.sqrt(),
+                    Builtin::Sin => n.sin(),
+                    Builtin::Cos => n.cos(),
+                    Builtin::Tan => n.tan(),
+                    Builtin::Log => n.ln(),
+                    _ => n.exp(),
+                };
+                self.push_number(result);
+            }
+            Builtin::Random => {
+                let r = self.next_random();
+                self.push_number(r);
+            }
+            Builtin::RandInt => {
+                // `1 6 randint` pushes a whole number from 1 to 6, both included
+                let hi = self.pop_number(span, &x)?;
+                let lo = self.pop_number(span, &x)?;
+                if lo.fract() != 0.0 || hi.fract() != 0.0 || lo > hi {
+                    return Err(RuntimeError::ValueError(
+                        span.clone(),
+                        format!("{}", x),
+                        "range".to_string(),
+                        format!("{} to {}", lo, hi),
+                    ));
+                }
+                let r = self.next_random();
+                self.push_number(lo + (r * (hi - lo + 1.0)).floor());
+            }
+            Builtin::Time => {
+                // seconds since 1970-01-01 00:00:00 UTC, with fractions
+                let t = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map_or(0.0, |d| d.as_secs_f64());
+                self.push_number(t);
+            }
+            Builtin::Clock => {
+                // seconds since the program started, never goes back like `time` can
+                let t = self.epoch.elapsed().as_secs_f64();
+                self.push_number(t);
+            }
+            Builtin::StrFTime => {
+                // `time "%Y-%m-%d %H:%M:%S" strftime`
+                let format = self.pop_string(span, &x)?;
+                let t = self.pop_number(span, &x)?;
+                match strftime(&format, t) {
+                    Ok(s) => self.push_string(s),
+                    Err(c) => {
+                        return Err(RuntimeError::ValueError(
+                            span.clone(),
+                            format!("{}", x),
+                            "format".to_string(),
+                            format!("\"%{}\"", c),
+                        ))
+                    }
+                }
+            }
+            Builtin::Argc => self.push_number(self.args.len() as f64),
+            Builtin::Argv => {
+                // `0 argv` is the first argument after `--`
+                let n = self.pop_number(span, &x)?;
+                match self.args.get(n as usize) {
+                    Some(a) if n >= 0.0 && n.fract() == 0.0 => self.push_string(a.clone()),
+                    _ => {
+                        return Err(RuntimeError::ValueError(
+                            span.clone(),
+                            format!("{}", x),
+                            format!("argument index (0 to {})", self.args.len() as isize - 1),
+                            n.to_string(),
+                        ))
+                    }
+                }
+            }
+            Builtin::Exists => {
+                let path = self.pop_string(span, &x)?;
+                self.push_number(Path::new(&path).exists() as i32 as f64);
+            }
+            Builtin::IsDir => {
+                let path = self.pop_string(span, &x)?;
+                self.push_number(Path::new(&path).is_dir() as i32 as f64);
+            }
+            Builtin::Remove => {
+                // directories are only removed when they're empty
+                let path = self.pop_string(span, &x)?;
+                let result = match std::fs::symlink_metadata(&path) {
+                    Ok(m) if m.is_dir() => std::fs::remove_dir(&path),
+                    _ => std::fs::remove_file(&path),
+                };
+                result.map_err(|e| {
+                    RuntimeError::IOError(span.clone(), format!("{}", x), e.to_string())
+                })?;
+            }
+            Builtin::MkDir => {
+                // like `mkdir -p`, missing parents are created too
+                let path = self.pop_string(span, &x)?;
+                std::fs::create_dir_all(&path).map_err(|e| {
+                    RuntimeError::IOError(span.clone(), format!("{}", x), e.to_string())
+                })?;
+            }
+            Builtin::ListDir => {
+                // pushes the names in the directory in alphabetical order, then how many there are
+                let path = self.pop_string(span, &x)?;
+                let mut names = std::fs::read_dir(&path)
+                    .and_then(|d| {
+                        d.map(|e| e.map(|e| e.file_name().to_string_lossy().to_string()))
+                            .collect::<std::io::Result<Vec<_>>>()
+                    })
+                    .map_err(|e| {
+                        RuntimeError::IOError(span.clone(), format!("{}", x), e.to_string())
+                    })?;
+                names.sort();
+                let n = names.len();
+                for name in names {
+                    self.push_string(name);
+                }
+                self.push_number(n as f64);
+            }
+            Builtin::Depth => self.push_number(self.stack.len() as f64),
+            Builtin::Sort => {
+                // sorts the counted values from smallest (deepest) to largest (top),
+                // and leaves the count on top so the result can be counted again
+                let mut values = self.pop_counted(span, &x)?;
+                let n = values.len();
+                if values.iter().all(|a| matches!(a, Data::Number(_))) {
+                    values.sort_by(|a, b| match (a, b) {
+                        (Data::Number(a), Data::Number(b)) => a.total_cmp(b),
+                        _ => unreachable!(),
+                    });
+                } else if values.iter().all(|a| matches!(a, Data::String(_))) {
+                    values.sort_by(|a, b| match (a, b) {
+                        (Data::String(a), Data::String(b)) => a.cmp(b),
+                        _ => unreachable!(),
+                    });
+                } else {
+                    return Err(RuntimeError::UnexpectedType(
+                        span.clone(),
+                        format!("{}", x),
+                        "all numbers or all strings".to_string(),
+                        "a mix of types".to_string(),
+                    ));
+                }
+                for a in values {
+                    self.stack.push_front(a);
+                }
+                self.push_number(n as f64);
+            }
+            Builtin::SortBy => {
+                // like `sort`, with a quotation that gets two values `a b` and leaves
+                // whether `a` goes before `b`
+                let q = match self.pop() {
+                    Some(Data::Quote(q)) => q,
+                    Some(a) => {
+                        return Err(RuntimeError::UnexpectedType(
+                            span.clone(),
+                            format!("{}", x),
+                            "quote".to_string(),
+                            format!("{}", a),
+                        ))
+                    }
+                    None => {
+                        return Err(RuntimeError::StackUnderflow(
+                            span.clone(),
+                            format!("{}", x),
+                            2,
+                        ))
+                    }
+                };
+                let values = self.pop_counted(span, &x)?;
+                let n = values.len();
+                for a in self.merge_sort(span, &q, values)? {
+                    self.stack.push_front(a);
+                }
+                self.push_number(n as f64);
+            }
+            Builtin::Format => {
+                // `10 34 "this {} is {} formatted" format` is "this 34 is 10 formatted":
+                // each `{}` takes the next value from the top. `{{` and `}}` are braces
+                let template = self.pop_string(span, &x)?;
+                let pieces = format_pieces(&template);
+                let wanted = pieces.iter().filter(|p| p.is_none()).count();
+                if self.stack.len() < wanted {
+                    return Err(RuntimeError::StackUnderflow(
+                        span.clone(),
+                        format!("{}", x),
+                        wanted + 1,
+                    ));
+                }
+                let mut out = String::new();
+                for p in pieces {
+                    match p {
+                        Some(text) => out.push_str(&text),
+                        None => match self.pop() {
+                            Some(Data::String(s)) => out.push_str(&s),
+                            Some(Data::Number(n)) => out.push_str(&n.to_string()),
+                            _ => {
+                                return Err(RuntimeError::UnexpectedType(
+                                    span.clone(),
+                                    format!("{}", x),
+                                    "number or string".to_string(),
+                                    "quote".to_string(),
+                                ))
+                            }
+                        },
+                    }
+                }
+                self.push_string(out);
+            }
+        }
+        Ok(())
+    }
+
+    fn unop(&mut self, span: &TokenSpan, x: UnaryOp) -> Result<(), RuntimeError> {
+        if let Some(a) = self.pop() {
+            match a {
+                Data::Number(n) => match x {
+                    UnaryOp::Trace => {
+                        writeln!(self.stdout, "number {}", n).map_err(write_error(span, &x))?
+                    }
+                    UnaryOp::Dup => {
+                        self.push_number(n);
+                        self.push_number(n);
+                    }
+                    UnaryOp::Drop => {}
+                    UnaryOp::BNot => self.push_number(!(n as i32) as f64),
+                },
+                Data::String(s) => match x {
+                    UnaryOp::Trace => {
+                        writeln!(self.stdout, "string \"{}\"", s).map_err(write_error(span, &x))?
+                    }
+                    UnaryOp::Dup => {
+                        self.push_string(s.clone());
+                        self.push_string(s);
+                    }
+                    UnaryOp::Drop => {}
+                    UnaryOp::BNot => {
+                        return Err(RuntimeError::UnexpectedType(
+                            span.clone(),
+                            format!("{}", x),
+                            "number".to_string(),
+                            "string".to_string(),
+                        ))
+                    }
+                },
+                Data::Quote(q) => match x {
+                    UnaryOp::Trace => {
+                        writeln!(self.stdout, "quote").map_err(write_error(span, &x))?
+                    }
+                    UnaryOp::Dup => {
+                        self.stack.push_front(Data::Quote(Rc::clone(&q)));
+                        self.stack.push_front(Data::Quote(q));
+                    }
+                    UnaryOp::Drop => {}
+                    UnaryOp::BNot => {
+                        return Err(RuntimeError::UnexpectedType(
+                            span.clone(),
+                            format!("{}", x),
+                            "number".to_string(),
+                            "quote".to_string(),
+                        ))
+                    }
+                },
+            }
+        } else {
+            return Err(RuntimeError::StackUnderflow(
+                span.clone(),
+                format!("{}", x),
+                1,
+            ));
+        }
+        Ok(())
+    }
+
+    fn binop(&mut self, span: &TokenSpan, x: BinaryOp) -> Result<(), RuntimeError> {
+        if let (Some(a), Some(b)) = (self.pop(), self.pop()) {
+            match (a, b) {
+                // these only move values around, so they work with values of any type
+                (a, b) if matches!(x, BinaryOp::Swap | BinaryOp::Over) => {
+                    if let BinaryOp::Over = x {
+                        self.stack.push_front(b.clone());
+                    }
+                    self.stack.push_front(a);
+                    self.stack.push_front(b);
+                }
+                (Data::Number(n1), Data::Number(n2)) => match x {
+                    BinaryOp::Add => self.push_number(n1 + n2),
+                    BinaryOp::Sub => self.push_number(n1 - n2),
+                    BinaryOp::Mul => self.push_number(n1 * n2),
+                    BinaryOp::Div => self.push_number(n1 / n2),
+                    BinaryOp::Mod => self.push_number(n1 % n2),
+                    BinaryOp::Exp => self.push_number(n1.powf(n2)),
                     BinaryOp::Eq => self.push_number((n1 == n2) as i32 as f64),
                     BinaryOp::Ne => self.push_number((n1 != n2) as i32 as f64),
                     BinaryOp::Lt => self.push_number((n1 < n2) as i32 as f64),
@@ -394,15 +2098,7 @@ impl<'a> Runtime<'a> {
                     BinaryOp::Shr => self.push_number(((n1 as i32) >> (n2 as i32)) as f64),
                     BinaryOp::Bor => self.push_number(((n1 as i32) | (n2 as i32)) as f64),
                     BinaryOp::Band => self.push_number(((n1 as i32) & (n2 as i32)) as f64),
-                    BinaryOp::Swap => {
-                        self.push_number(n1);
-                        self.push_number(n2);
-                    }
-                    BinaryOp::Over => {
-                        self.push_number(n2);
-                        self.push_number(n1);
-                        self.push_number(n2);
-                    }
+                    BinaryOp::Swap | BinaryOp::Over => unreachable!(),
                 },
                 (ref i @ Data::String(ref s1), ref j @ Data::String(ref s2)) => match x {
                     BinaryOp::Add => self.push_string(s1.to_owned() + s2),
@@ -413,18 +2109,9 @@ impl<'a> Runtime<'a> {
                     // BinaryOp::Gt => self.push_number((s1 > s2) as i32 as f64),
                     // BinaryOp::Le => self.push_number((s1 <= s2) as i32 as f64),
                     // BinaryOp::Ge => self.push_number((s1 >= s2) as i32 as f64),
-                    BinaryOp::Swap => {
-                        self.push_string(s1.to_string());
-                        self.push_string(s2.to_string());
-                    }
-                    BinaryOp::Over => {
-                        self.push_string(s2.clone());
-                        self.push_string(s1.clone());
-                        self.push_string(s2.to_string());
-                    }
                     _ => {
                         return Err(RuntimeError::UnexpectedType(
-                            span,
+                            span.clone(),
                             format!("{}", x),
                             "numbers".to_string(),
                             format!("({}, {})", i, j),
@@ -433,7 +2120,7 @@ impl<'a> Runtime<'a> {
                 },
                 (a, b) => {
                     return Err(RuntimeError::UnexpectedType(
-                        span,
+                        span.clone(),
                         format!("{}", x),
                         "numbers or strings".to_string(),
                         format!("({}, {})", &a, &b),
@@ -441,154 +2128,941 @@ impl<'a> Runtime<'a> {
                 }
             }
         } else {
-            return Err(RuntimeError::StackUnderflow(span, format!("{}", x), 2));
+            return Err(RuntimeError::StackUnderflow(
+                span.clone(),
+                format!("{}", x),
+                2,
+            ));
         }
         Ok(())
     }
 
-    fn run_node(&mut self, n: &'a Node) -> Result<(), RuntimeError> {
+    // Runs a procedure's body on the current stack, checking its declared stack effect.
+    // Errors inside it are wrapped with the place where it was called from.
+    fn call_procedure(
+        &mut self,
+        span: &TokenSpan,
+        name: &str,
+        body: &'a [Node],
+        effect: Option<&'a StackEffect>,
+    ) -> Result<(), RuntimeError> {
+        let depth = self.stack.len();
+        if let Some(e) = effect {
+            if depth < e.inputs.len() {
+                return Err(RuntimeError::StackUnderflow(
+                    span.clone(),
+                    name.to_string(),
+                    e.inputs.len(),
+                ));
+            }
+        }
+        self.call_quote(span, body)?;
+        if let Some(e) = effect {
+            // number of elements left by the procedure after consuming its inputs
+            let left = self.stack.len() as isize - (depth - e.inputs.len()) as isize;
+            if left != e.outputs.len() as isize {
+                return Err(RuntimeError::StackEffectMismatch(
+                    span.clone(),
+                    name.to_string(),
+                    e.to_string(),
+                    e.outputs.len(),
+                    left,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Runs a quotation (or the body of a procedure) on the current stack. Errors inside it
+    // are reported with the call site, and nesting too deeply is an error instead of
+    // overflowing the interpreter's own stack.
+    fn call_quote(&mut self, span: &TokenSpan, q: &[Node]) -> Result<(), RuntimeError> {
+        if self.calls >= self.max_calls {
+            return Err(RuntimeError::CallStackOverflow(
+                span.clone(),
+                self.max_calls,
+            ));
+        }
+        self.calls += 1;
+        let result = self.run_block(span, q);
+        self.calls -= 1;
+        // `stop` in a procedure ends the loop it was called from, but with no loop around
+        // the call it must not be left pending for the next one
+        if self.loops == 0 {
+            self.stop = false;
+        }
+        result.map_err(|e| RuntimeError::ProcedureError {
+            call: span.clone(),
+            inner: Box::new(e),
+        })
+    }
+
+    fn check_interrupted(&self, span: &TokenSpan) -> Result<(), RuntimeError> {
+        if INTERRUPTED.load(Ordering::Relaxed) {
+            let top = self.peek().map(|a| a.describe());
+            return Err(RuntimeError::Interrupted(span.clone(), top));
+        }
+        Ok(())
+    }
+
+    fn check_deadline(&self, span: &TokenSpan) -> Result<(), RuntimeError> {
+        if let Some((d, t)) = self.deadline {
+            if Instant::now() >= d {
+                return Err(RuntimeError::Timeout(span.clone(), t));
+            }
+        }
+        Ok(())
+    }
+
+    // `--trace`: the node and the last few elements of the stack, the top on the right
+    fn trace_node(&mut self, n: &Node) -> Result<(), RuntimeError> {
+        const SHOWN: usize = 5;
+        let mut top: Vec<String> = self
+            .stack
+            .iter()
+            .take(SHOWN)
+            .map(|x| match x {
+                Data::Number(n) => n.to_string(),
+                Data::String(s) => format!("{s:?}"),
+                Data::Quote(_) => "<quote>".to_string(),
+            })
+            .collect();
+        if self.stack.len() > SHOWN {
+            top.push("...".to_string());
+        }
+        top.reverse();
+        let s = n.span();
+        self.stdout.flush().map_err(write_error(s, &"--trace"))?;
+        writeln!(
+            self.stderr,
+            "trace at {}:{}:{}: {} [{}]",
+            s.filename,
+            s.line,
+            s.col,
+            n,
+            top.join(" ")
+        )
+        .map_err(write_error(s, &"--trace"))
+    }
+
+    // Counts one step of the program and enforces Ctrl+C and the limits (--timeout,
+    // --max-instructions and --max-memory).
+    fn step(&mut self, span: &TokenSpan) -> Result<(), RuntimeError> {
+        self.check_interrupted(span)?;
+        self.steps += 1;
+        if let Some(max) = self.max_steps {
+            if self.steps > max {
+                return Err(RuntimeError::InstructionLimit(span.clone(), max));
+            }
+        }
+        if let Some((base, max)) = self.max_memory {
+            if ALLOCATED.load(Ordering::Relaxed).saturating_sub(base) > max {
+                return Err(RuntimeError::MemoryLimit(span.clone(), max));
+            }
+        }
+        // looking at the clock is slow, so it's only done once in a while
+        if self.deadline.is_some() && self.steps.is_multiple_of(1024) {
+            self.check_deadline(span)?;
+        }
+        Ok(())
+    }
+
+    fn run_node(&mut self, n: &Node) -> Result<(), RuntimeError> {
+        self.step(n.span())?;
+        if self.tracing {
+            self.trace_node(n)?;
+        }
         match n {
             Node::If(i, e, s) => {
                 if let Some(a) = self.pop() {
-                    match a {
-                        Data::Number(n) => {
-                            if n > 0.0 {
-                                // negative values or zero = false
-                                self.run_block(i)?;
-                            } else {
-                                if let Some(els) = e {
-                                    self.run_block(els)?;
-                                }
-                            }
-                        }
-                        Data::String(x) => {
-                            if x.len() > 0 {
-                                // empty string = false
-                                self.run_block(i)?;
-                            } else {
-                                if let Some(els) = e {
-                                    self.run_block(els)?;
-                                }
-                            }
-                        }
+                    // negative values, zero and empty strings are false
+                    if a.is_truthy() {
+                        self.run_block(s, i)?;
+                    } else if let Some(els) = e {
+                        self.run_block(s, els)?;
                     }
                 } else {
                     return Err(RuntimeError::StackUnderflow(s.clone(), "if".to_string(), 1));
                 }
             }
-            Node::Loop(l, _) => {
-                while !self.stop {
-                    self.run_block(l)?;
-
-                    if self.stop {
-                        self.stop = false;
-                        break;
+            Node::Loop(..)
+            | Node::Times(..)
+            | Node::For(..)
+            | Node::Until(..)
+            | Node::While(..) => {
+                // counted even when the loop fails, `try` can keep the program running
+                self.loops += 1;
+                let result = self.run_loop(n);
+                self.loops -= 1;
+                result?;
+            }
+            Node::TypeCase(c, e, s) => {
+                // the value is only inspected, it stays on the stack
+                let kind = match self.peek() {
+                    Some(a) => format!("{}", a),
+                    None => {
+                        return Err(RuntimeError::StackUnderflow(
+                            s.clone(),
+                            "typecase".to_string(),
+                            1,
+                        ));
+                    }
+                };
+                if let Some((_, body)) = c.iter().find(|(k, _)| *k == kind) {
+                    self.run_block(s, body)?;
+                } else if let Some(els) = e {
+                    self.run_block(s, els)?;
+                }
+            }
+            Node::Try(t, c, s) => {
+                let saved = self.stack.clone();
+                if let Err(e) = self.run_block(s, t) {
+                    if e.is_fatal() {
+                        return Err(e);
                     }
+                    // the stack goes back to how it was before `try`, with the message on top
+                    self.stack = saved;
+                    self.push_string(error_message(&e));
+                    self.run_block(s, c)?;
                 }
             }
             Node::Number(n, _) => self.push_number(*n),
             Node::String(v, _) => self.push_string(v.to_string()),
-            Node::Operation(op, s) => {
-                let s = s.clone(); // TODO: this is a hack, fix it
-                match op {
-                    OpKind::Add => self.binop(s, BinaryOp::Add)?,
-                    OpKind::Sub => self.binop(s, BinaryOp::Sub)?,
-                    OpKind::Mul => self.binop(s, BinaryOp::Mul)?,
-                    OpKind::Div => self.binop(s, BinaryOp::Div)?,
-                    OpKind::Mod => self.binop(s, BinaryOp::Mod)?,
-                    OpKind::Exp => self.binop(s, BinaryOp::Exp)?,
-                    OpKind::Gt => self.binop(s, BinaryOp::Gt)?,
-                    OpKind::Lt => self.binop(s, BinaryOp::Lt)?,
-                    OpKind::Eq => self.binop(s, BinaryOp::Eq)?,
-                    OpKind::Ge => self.binop(s, BinaryOp::Ge)?,
-                    OpKind::Le => self.binop(s, BinaryOp::Le)?,
-                    OpKind::Ne => self.binop(s, BinaryOp::Ne)?,
-                    OpKind::Shl => self.binop(s, BinaryOp::Shl)?,
-                    OpKind::Shr => self.binop(s, BinaryOp::Shr)?,
-                    OpKind::Bor => self.binop(s, BinaryOp::Bor)?,
-                    OpKind::Band => self.binop(s, BinaryOp::Band)?,
-                    OpKind::Swap => self.binop(s, BinaryOp::Swap)?,
-                    OpKind::Over => self.binop(s, BinaryOp::Over)?,
-                    OpKind::BNot => self.unop(s, UnaryOp::BNot)?,
-                    OpKind::Dup => self.unop(s, UnaryOp::Dup)?,
-                    OpKind::Drop => self.unop(s, UnaryOp::Drop)?,
-                    OpKind::Trace => self.unop(s, UnaryOp::Trace)?,
-                    OpKind::Rot => {
-                        if let (Some(a), Some(b), Some(c)) = (self.pop(), self.pop(), self.pop()) {
-                            self.stack.push_front(b);
-                            self.stack.push_front(a);
-                            self.stack.push_front(c);
-                        } else {
-                            return Err(RuntimeError::StackUnderflow(s, "rot".to_string(), 3));
+            Node::Operation(op, s) => match op {
+                OpKind::Add => self.binop(s, BinaryOp::Add)?,
+                OpKind::Sub => self.binop(s, BinaryOp::Sub)?,
+                OpKind::Mul => self.binop(s, BinaryOp::Mul)?,
+                OpKind::Div => self.binop(s, BinaryOp::Div)?,
+                OpKind::Mod => self.binop(s, BinaryOp::Mod)?,
+                OpKind::Exp => self.binop(s, BinaryOp::Exp)?,
+                OpKind::Gt => self.binop(s, BinaryOp::Gt)?,
+                OpKind::Lt => self.binop(s, BinaryOp::Lt)?,
+                OpKind::Eq => self.binop(s, BinaryOp::Eq)?,
+                OpKind::Ge => self.binop(s, BinaryOp::Ge)?,
+                OpKind::Le => self.binop(s, BinaryOp::Le)?,
+                OpKind::Ne => self.binop(s, BinaryOp::Ne)?,
+                OpKind::Shl => self.binop(s, BinaryOp::Shl)?,
+                OpKind::Shr => self.binop(s, BinaryOp::Shr)?,
+                OpKind::Bor => self.binop(s, BinaryOp::Bor)?,
+                OpKind::Band => self.binop(s, BinaryOp::Band)?,
+                OpKind::Swap => self.binop(s, BinaryOp::Swap)?,
+                OpKind::Over => self.binop(s, BinaryOp::Over)?,
+                OpKind::BNot => self.unop(s, UnaryOp::BNot)?,
+                OpKind::Dup => self.unop(s, UnaryOp::Dup)?,
+                OpKind::Drop => self.unop(s, UnaryOp::Drop)?,
+                OpKind::Trace => self.unop(s, UnaryOp::Trace)?,
+                OpKind::Rot => {
+                    if let (Some(a), Some(b), Some(c)) = (self.pop(), self.pop(), self.pop()) {
+                        self.stack.push_front(b);
+                        self.stack.push_front(a);
+                        self.stack.push_front(c);
+                    } else {
+                        return Err(RuntimeError::StackUnderflow(
+                            s.clone(),
+                            "rot".to_string(),
+                            3,
+                        ));
+                    }
+                    Ok(())
+                }?,
+                OpKind::TwoDup | OpKind::TwoSwap | OpKind::TwoDrop | OpKind::Nip | OpKind::Tuck => {
+                    let (name, n) = match op {
+                        OpKind::TwoDup => ("2dup", 2),
+                        OpKind::TwoSwap => ("2swap", 4),
+                        OpKind::TwoDrop => ("2drop", 2),
+                        OpKind::Nip => ("nip", 2),
+                        _ => ("tuck", 2),
+                    };
+                    if self.stack.len() < n {
+                        return Err(RuntimeError::StackUnderflow(s.clone(), name.to_string(), n));
+                    }
+                    // the front of the stack is its top: [b, a, ...] for `a b`
+                    match op {
+                        OpKind::TwoDup => {
+                            self.stack.push_front(self.stack[1].clone());
+                            self.stack.push_front(self.stack[1].clone());
+                        }
+                        OpKind::TwoSwap => {
+                            self.stack.swap(0, 2);
+                            self.stack.swap(1, 3);
                         }
-                        Ok(())
-                    }?,
-                    OpKind::Stop => {
-                        self.stop = true;
+                        OpKind::TwoDrop => {
+                            self.stack.drain(..2);
+                        }
+                        OpKind::Nip => {
+                            self.stack.remove(1);
+                        }
+                        _ => self.stack.insert(2, self.stack[0].clone()),
                     }
                 }
-            }
+                OpKind::Pick | OpKind::Roll => {
+                    let name = if matches!(op, OpKind::Pick) {
+                        "pick"
+                    } else {
+                        "roll"
+                    };
+                    let n = self.pop_number(s, &name)?;
+                    if n < 0.0 || n.fract() != 0.0 {
+                        return Err(RuntimeError::ValueError(
+                            s.clone(),
+                            name.to_string(),
+                            "position".to_string(),
+                            n.to_string(),
+                        ));
+                    }
+                    // compared before the cast, which saturates for huge positions
+                    if n >= self.stack.len() as f64 {
+                        return Err(RuntimeError::StackUnderflow(
+                            s.clone(),
+                            name.to_string(),
+                            (n as usize).saturating_add(1),
+                        ));
+                    }
+                    let n = n as usize;
+                    let a = if matches!(op, OpKind::Pick) {
+                        self.stack[n].clone()
+                    } else {
+                        self.stack.remove(n).unwrap()
+                    };
+                    self.stack.push_front(a);
+                }
+                OpKind::Stop => {
+                    self.stop = true;
+                }
+            },
             Node::Word(w, s) => {
-                let s = s.clone();
+                let b = Builtin::from_word(w);
                 match w.as_str() {
-                    "println" => self.builtin(s, Builtin::Println)?,
-                    "print" => self.builtin(s, Builtin::Print)?,
-                    "eprint" => self.builtin(s, Builtin::EPrint)?,
-                    "eprintln" => self.builtin(s, Builtin::EPrintln)?,
-                    "readln" => self.builtin(s, Builtin::Readln)?,
-                    "read" => self.builtin(s, Builtin::Read)?,
-                    "exit" => self.builtin(s, Builtin::Exit)?,
-                    "tostring" => self.builtin(s, Builtin::ToString)?,
-                    "tonumber" => self.builtin(s, Builtin::ToNumber)?,
+                    _ if b.as_ref().is_some_and(|b| b.is_reserved()) => {
+                        self.builtin(s, b.unwrap())?
+                    }
+                    // inside a `times` loop, `i` is the current iteration (starting at 0),
+                    // and so is the name given to a `for` loop
+                    x if self.counter(x).is_some() => {
+                        let i = self.counter(x).unwrap();
+                        self.push_number(i);
+                    }
                     _ => {
-                        if let Some(p) = self.namespace.procs.iter().find(|p| p.0 == *w) {
-                            if let Err(e) = self.run_block(&p.1) {
-                                return Err(RuntimeError::ProcedureError {
-                                    call: s,
-                                    inner: Box::new(e),
-                                });
+                        if let Some(h) = self.namespace.hosts.iter().find(|h| h.0 == *w) {
+                            let f = Rc::clone(&h.1);
+                            if let Err(e) = f(self) {
+                                return Err(RuntimeError::HostError(s.clone(), w.to_string(), e));
                             }
+                        } else if let Some(p) = self.namespace.procs.iter().find(|p| p.0 == *w) {
+                            let (body, effect) = (p.1, p.2);
+                            self.call_procedure(s, w, body, effect)?;
                         } else if let Some(d) = self.namespace.defs.iter().find(|p| p.0 == *w) {
-                            match &d.1 {
-                                Data::Number(n) => self.push_number(*n),
-                                Data::String(s) => self.push_string(String::from(s)),
-                            }
+                            self.stack.push_front(d.1.clone());
+                        } else if let Some(b) = b {
+                            self.builtin(s, b)?
+                        } else if let Some(c) = constant(w) {
+                            self.push_number(c);
                         } else {
-                            return Err(RuntimeError::InvalidWord(s, w.to_string()));
+                            return Err(RuntimeError::InvalidWord(s.clone(), w.to_string()));
                         }
                     }
                 }
             }
             Node::Proc(..) => {}
             Node::Def(..) => {}
+            Node::Quote(q, _) => self.stack.push_front(Data::Quote(Rc::clone(q))),
+            Node::Debug(label, s) => {
+                if self.debug {
+                    let top = match self.peek() {
+                        Some(a) => a.describe(),
+                        None => "the stack is empty".to_string(),
+                    };
+                    let label = label
+                        .as_ref()
+                        .map_or(String::new(), |l| format!(" ({})", l));
+                    self.stdout.flush().map_err(write_error(s, &"debug"))?;
+                    writeln!(
+                        self.stderr,
+                        "debug at {}:{}:{}{}: {}",
+                        s.filename, s.line, s.col, label, top
+                    )
+                    .map_err(write_error(s, &"debug"))?;
+                }
+            }
         }
         Ok(())
     }
 
     pub fn run(&mut self) -> Result<(), RuntimeError> {
         self.pre_execution_scan()?;
+        // looked up before running anything, so that a typo doesn't run the whole program
+        let span = TokenSpan {
+            filename: "--entry".into(),
+            line: 0,
+            col: 0,
+        };
+        let entry = match &self.entry {
+            Some(name) => match self.namespace.procs.iter().find(|p| p.0 == *name) {
+                Some(p) => Some((p.0.clone(), p.1, p.2)),
+                None => return Err(RuntimeError::InvalidWord(span, name.to_string())),
+            },
+            None => None,
+        };
+        // when the program already failed, its error is the one worth reporting
         for n in self.input {
-            self.run_node(n)?;
+            if let Err(e) = self.run_node(n) {
+                let _ = self.flush();
+                return Err(e);
+            }
+            self.stop = false;
         }
-        Ok(())
+        // called like any other procedure, with its stack effect and the call depth checked
+        if let Some((name, body, effect)) = entry {
+            if let Err(e) = self.call_procedure(&span, &name, body, effect) {
+                let _ = self.flush();
+                return Err(e);
+            }
+        }
+        let end = self.input.last().map_or(&span, |n| n.span());
+        self.flush().map_err(write_error(end, &"flush"))
     }
 
-    fn run_block(&mut self, b: &'a Vec<Node>) -> Result<(), RuntimeError> {
-        for n in b {
-            self.run_node(n)?;
+    // Makes `run` call the procedure `name` after the top-level code has run.
+    pub fn set_entry(&mut self, name: &str) {
+        self.entry = Some(name.to_string());
+    }
+
+    // The arguments `argc` and `argv` give to the program.
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
+
+    // Calls the procedure `name` from Rust. The arguments are pushed in order onto an
+    // empty stack and whatever is left on it afterwards is returned (bottom first).
+    // The program's own stack is left untouched. Errors inside it are wrapped with a
+    // `host` call site, like calls from Pile code are wrapped with theirs.
+    pub fn call_proc(&mut self, name: &str, args: &[Data]) -> Result<Vec<Data>, RuntimeError> {
+        self.pre_execution_scan()?;
+        let span = TokenSpan {
+            filename: "host".into(),
+            line: 0,
+            col: 0,
+        };
+        let (body, effect) = match self.namespace.procs.iter().find(|p| p.0 == name) {
+            Some(p) => (p.1, p.2),
+            None => return Err(RuntimeError::InvalidWord(span, name.to_string())),
+        };
+        let saved = std::mem::take(&mut self.stack);
+        for a in args {
+            self.stack.push_front(a.clone());
+        }
+        // the same checks as a call from Pile code: stack effect, call depth and error trace
+        let result = self.call_procedure(&span, name, body, effect);
+        let values = std::mem::replace(&mut self.stack, saved);
+        result.map(|_| values.into_iter().rev().collect())
+    }
+
+    // A stable merge sort for `sortby`. The comparison runs Pile code, which can fail or
+    // disagree with itself, so the standard library's sort isn't used.
+    fn merge_sort(
+        &mut self,
+        span: &TokenSpan,
+        q: &[Node],
+        mut values: Vec<Data>,
+    ) -> Result<Vec<Data>, RuntimeError> {
+        if values.len() <= 1 {
+            return Ok(values);
+        }
+        let right = values.split_off(values.len() / 2);
+        let left = self.merge_sort(span, q, values)?;
+        let right = self.merge_sort(span, q, right)?;
+        let mut merged = Vec::with_capacity(left.len() + right.len());
+        let (mut left, mut right) = (left.into_iter().peekable(), right.into_iter().peekable());
+        while let (Some(l), Some(r)) = (left.peek(), right.peek()) {
+            // asks whether `r` goes before `l`, so equal values keep their order
+            let (l, r) = (l.clone(), r.clone());
+            self.stack.push_front(r);
+            self.stack.push_front(l);
+            self.call_quote(span, q)?;
+            let Some(first) = self.pop() else {
+                return Err(RuntimeError::StackUnderflow(
+                    span.clone(),
+                    "sortby".to_string(),
+                    1,
+                ));
+            };
+            if first.is_truthy() {
+                merged.push(right.next().unwrap());
+            } else {
+                merged.push(left.next().unwrap());
+            }
+        }
+        merged.extend(left);
+        merged.extend(right);
+        Ok(merged)
+    }
+
+    // Runs `body` `n` times with the counter `name` going from 0 to n - 1.
+    fn run_counted(
+        &mut self,
+        span: &TokenSpan,
+        name: &str,
+        n: f64,
+        body: &[Node],
+    ) -> Result<(), RuntimeError> {
+        self.counters.push((name.to_string(), 0.0));
+        let mut result = Ok(());
+        let mut i = 0.0;
+        while i < n && !self.stop {
+            self.counters.last_mut().unwrap().1 = i;
+            // like in `loop`, each round of an empty body is a step by itself
+            result = if body.is_empty() {
+                self.step(span)
+            } else {
+                self.run_block(span, body)
+            };
+            if result.is_err() {
+                break;
+            }
+            if self.stop {
+                self.stop = false;
+                break;
+            }
+            i += 1.0;
+        }
+        self.counters.pop();
+        result
+    }
+
+    // The value of the innermost loop counter called `name`.
+    fn counter(&self, name: &str) -> Option<f64> {
+        self.counters
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .map(|(_, i)| *i)
+    }
+
+    // Runs a `loop`, `times`, `for`, `until` or `while` node. `stop` ends the loop after
+    // the current round of its body, and a loop doesn't start while a `stop` is pending.
+    fn run_loop(&mut self, n: &Node) -> Result<(), RuntimeError> {
+        match n {
+            Node::Loop(l, s) => {
+                while !self.stop {
+                    // an empty body runs no nodes, each round is a step by itself
+                    if l.is_empty() {
+                        self.step(s)?;
+                    }
+                    self.run_block(s, l)?;
+
+                    if self.stop {
+                        self.stop = false;
+                        break;
+                    }
+                }
+            }
+            Node::Times(l, s) => {
+                let n = self.pop_number(s, &"times")?;
+                self.run_counted(s, "i", n, l)?;
+            }
+            Node::For(name, l, s) => {
+                let n = self.pop_number(s, &"for")?;
+                self.run_counted(s, name, n, l)?;
+            }
+            Node::Until(c, l, s) => {
+                while !self.stop {
+                    // the body always runs at least once, the condition is checked after it
+                    self.run_block(s, l)?;
+                    if !self.stop {
+                        self.run_block(s, c)?;
+                    }
+                    if self.stop {
+                        self.stop = false;
+                        break;
+                    }
+                    match self.pop() {
+                        Some(a) => {
+                            if a.is_truthy() {
+                                break;
+                            }
+                        }
+                        None => {
+                            return Err(RuntimeError::StackUnderflow(
+                                s.clone(),
+                                "until".to_string(),
+                                1,
+                            ));
+                        }
+                    }
+                }
+            }
+            Node::While(c, l, s) => {
+                while !self.stop {
+                    // the condition is checked before every run of the body
+                    self.run_block(s, c)?;
+                    if self.stop {
+                        self.stop = false;
+                        break;
+                    }
+                    match self.pop() {
+                        Some(a) => {
+                            if !a.is_truthy() {
+                                break;
+                            }
+                        }
+                        None => {
+                            return Err(RuntimeError::StackUnderflow(
+                                s.clone(),
+                                "while".to_string(),
+                                1,
+                            ));
+                        }
+                    }
+                    self.run_block(s, l)?;
+                    if self.stop {
+                        self.stop = false;
+                        break;
+                    }
+                }
+            }
+            _ => unreachable!("not a loop"),
         }
         Ok(())
     }
 
-    fn push_number(&mut self, n: f64) {
+    // Blocks nested in each other take the interpreter's stack like calls do, so they are
+    // counted as well. `span` is the node (or call) the block belongs to.
+    fn run_block(&mut self, span: &TokenSpan, b: &[Node]) -> Result<(), RuntimeError> {
+        if self.levels >= self.max_calls.saturating_mul(LEVELS_PER_CALL) {
+            return Err(RuntimeError::CallStackOverflow(
+                span.clone(),
+                self.max_calls,
+            ));
+        }
+        self.levels += 1;
+        let result = b.iter().try_for_each(|n| self.run_node(n));
+        self.levels -= 1;
+        result
+    }
+
+    // A number in [0, 1) from a xorshift64* generator. Good enough for games and
+    // simulations, not for anything that has to be secret.
+    fn next_random(&mut self) -> f64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let n = self.rng.wrapping_mul(0x2545F4914F6CDD1D);
+        // the top 53 bits fill the mantissa of an f64
+        (n >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // Wraps `text` in the escape codes for the given attributes, if colors are enabled.
+    fn style(&self, text: &str, codes: &[u8]) -> String {
+        if !self.colors || codes.is_empty() {
+            return text.to_string();
+        }
+        let codes: Vec<String> = codes.iter().map(|c| c.to_string()).collect();
+        format!("\x1b[{}m{}\x1b[0m", codes.join(";"), text)
+    }
+
+    // Interrupts the program with a `Timeout` error if it's still running `seconds` from now.
+    // A timeout too far away to be represented is the same as no timeout at all.
+    pub fn set_timeout(&mut self, seconds: f64) {
+        self.deadline = Duration::try_from_secs_f64(seconds)
+            .ok()
+            .and_then(|d| Instant::now().checked_add(d))
+            .map(|d| (d, seconds));
+    }
+
+    // Stops the program with an `InstructionLimit` error once it has run `n` steps.
+    pub fn set_max_instructions(&mut self, n: usize) {
+        self.max_steps = Some(n);
+    }
+
+    // Stops the program with a `MemoryLimit` error once it takes `bytes` more of the heap
+    // than it does now. Needs `CountingAllocator` as the global allocator.
+    pub fn set_max_memory(&mut self, bytes: usize) {
+        self.max_memory = Some((ALLOCATED.load(Ordering::Relaxed), bytes));
+    }
+
+    // How deep procedure and quotation calls can be nested before `CallStackOverflow`.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_calls = depth;
+    }
+
+    // Makes `stylize` and friends emit escape codes (or not) regardless of where the
+    // output goes.
+    pub fn set_colors(&mut self, on: bool) {
+        self.colors = on;
+    }
+
+    // Turns `debug` into a no-op when `on` is false.
+    pub fn set_debug(&mut self, on: bool) {
+        self.debug = on;
+    }
+
+    // Prints every node to stderr before running it, with the top of the stack.
+    pub fn set_tracing(&mut self, on: bool) {
+        self.tracing = on;
+    }
+
+    // Shows or hides the warnings found before running the program.
+    pub fn set_warnings(&mut self, on: bool) {
+        self.warnings = on;
+    }
+
+    // Preallocates room for `n` more elements on the stack, for programs known to use a deep stack.
+    pub fn reserve_stack(&mut self, n: usize) {
+        self.stack.reserve(n);
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.stdout.flush()?;
+        self.stderr.flush()
+    }
+
+    pub fn push_number(&mut self, n: f64) {
         self.stack.push_front(Data::Number(n));
     }
 
-    fn push_string(&mut self, s: String) {
+    pub fn push_string(&mut self, s: String) {
         self.stack.push_front(Data::String(s));
     }
 
-    fn pop(&mut self) -> Option<Data> {
+    pub fn pop(&mut self) -> Option<Data> {
         self.stack.pop_front()
     }
+
+    // pops a value that must be a number for the operation `op` to work
+    fn pop_number(
+        &mut self,
+        span: &TokenSpan,
+        op: &impl std::fmt::Display,
+    ) -> Result<f64, RuntimeError> {
+        match self.pop() {
+            Some(Data::Number(n)) => Ok(n),
+            Some(a) => Err(RuntimeError::UnexpectedType(
+                span.clone(),
+                op.to_string(),
+                "number".to_string(),
+                format!("{}", a),
+            )),
+            None => Err(RuntimeError::StackUnderflow(
+                span.clone(),
+                op.to_string(),
+                1,
+            )),
+        }
+    }
+
+    // Pops a count and then that many values, for operations that work on a group of
+    // values (`1 2 3 3 sum`). The values are returned bottom first.
+    fn pop_counted(
+        &mut self,
+        span: &TokenSpan,
+        op: &impl std::fmt::Display,
+    ) -> Result<Vec<Data>, RuntimeError> {
+        let n = self.pop_number(span, op)?;
+        if n < 0.0 || n.fract() != 0.0 {
+            return Err(RuntimeError::ValueError(
+                span.clone(),
+                op.to_string(),
+                "count".to_string(),
+                n.to_string(),
+            ));
+        }
+        let n = n as usize;
+        if self.stack.len() < n {
+            return Err(RuntimeError::StackUnderflow(
+                span.clone(),
+                op.to_string(),
+                n,
+            ));
+        }
+        Ok(self.stack.drain(..n).rev().collect())
+    }
+
+    // Like `pop_counted`, for operations that only work on numbers.
+    fn pop_counted_numbers(
+        &mut self,
+        span: &TokenSpan,
+        op: &impl std::fmt::Display,
+    ) -> Result<Vec<f64>, RuntimeError> {
+        let values = self.pop_counted(span, op)?;
+        values
+            .into_iter()
+            .map(|a| match a {
+                Data::Number(n) => Ok(n),
+                _ => Err(RuntimeError::UnexpectedType(
+                    span.clone(),
+                    op.to_string(),
+                    "number".to_string(),
+                    format!("{}", a),
+                )),
+            })
+            .collect()
+    }
+
+    // pops a value that must be a string for the operation `op` to work
+    fn pop_string(
+        &mut self,
+        span: &TokenSpan,
+        op: &impl std::fmt::Display,
+    ) -> Result<String, RuntimeError> {
+        match self.pop() {
+            Some(Data::String(s)) => Ok(s),
+            Some(a) => Err(RuntimeError::UnexpectedType(
+                span.clone(),
+                op.to_string(),
+                "string".to_string(),
+                format!("{}", a),
+            )),
+            None => Err(RuntimeError::StackUnderflow(
+                span.clone(),
+                op.to_string(),
+                1,
+            )),
+        }
+    }
+
+    pub fn peek(&self) -> Option<&Data> {
+        self.stack.front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::{InputFile, Lexer, Span};
+    use crate::parser::Parser;
+
+    fn parse(source: &str) -> ProgramTree {
+        let f = InputFile {
+            name: "test",
+            content: source.chars().peekable(),
+        };
+        Parser::new(Lexer::new(f, Span { line: 1, col: 1 }))
+            .parse()
+            .unwrap()
+    }
+
+    fn runtime(p: &ProgramTree) -> Runtime<'_> {
+        Runtime::with_streams(
+            p,
+            Box::new(std::io::empty()),
+            Box::new(std::io::sink()),
+            Box::new(std::io::sink()),
+        )
+    }
+
+    // the error itself, without the procedure calls it went through
+    fn innermost(e: RuntimeError) -> RuntimeError {
+        match e {
+            RuntimeError::ProcedureError { inner, .. } => innermost(*inner),
+            e => e,
+        }
+    }
+
+    // Runs `source` with the given call depth, on a thread with the stack the CLI gives it.
+    // Errors can't leave the thread, so only the limit of a `CallStackOverflow` is kept.
+    fn run_with_depth(source: &'static str, depth: usize) -> Result<(), Option<usize>> {
+        std::thread::Builder::new()
+            .stack_size(stack_size(depth))
+            .spawn(move || {
+                let p = parse(source);
+                let mut r = runtime(&p);
+                r.set_max_call_depth(depth);
+                r.run().map_err(|e| match innermost(e) {
+                    RuntimeError::CallStackOverflow(_, n) => Some(n),
+                    _ => None,
+                })
+            })
+            .unwrap()
+            .join()
+            .unwrap()
+    }
+
+    #[test]
+    fn recursion_past_the_call_depth_is_an_error() {
+        assert_eq!(run_with_depth("proc r r end r", 200), Err(Some(200)));
+    }
+
+    #[test]
+    fn nested_blocks_count_towards_the_call_depth() {
+        let source = "proc r 1 if 1 if 1 if 1 if 1 if r end end end end end end r";
+        assert_eq!(run_with_depth(source, 200), Err(Some(200)));
+    }
+
+    #[test]
+    fn recursion_within_the_call_depth_works() {
+        let source = "proc f dup 199 = if drop else 1 + f end end 0 f";
+        assert_eq!(run_with_depth(source, 200), Ok(()));
+    }
+
+    #[test]
+    fn a_loop_that_never_stops_times_out() {
+        let p = parse("0 loop 1 + end");
+        let mut r = runtime(&p);
+        r.set_timeout(0.05);
+        assert!(matches!(r.run(), Err(RuntimeError::Timeout(..))));
+    }
+
+    #[test]
+    fn a_timeout_too_far_away_is_no_timeout() {
+        let p = parse("1 2 +");
+        let mut r = runtime(&p);
+        r.set_timeout(1e30);
+        assert!(r.run().is_ok());
+        assert!(matches!(r.pop(), Some(Data::Number(n)) if n == 3.0));
+    }
+
+    #[test]
+    fn pick_and_roll_check_the_position() {
+        let p = parse("1 2 3 2 pick");
+        let mut r = runtime(&p);
+        r.run().unwrap();
+        assert!(matches!(r.pop(), Some(Data::Number(n)) if n == 1.0));
+
+        for source in [
+            "1 2 3 3 pick",
+            "1 2 3 3 roll",
+            "1 2 1000000000000000000000000000000 pick",
+            "1 2 1000000000000000000000000000000 roll",
+        ] {
+            let p = parse(source);
+            let mut r = runtime(&p);
+            assert!(
+                matches!(r.run(), Err(RuntimeError::StackUnderflow(..))),
+                "{source}"
+            );
+        }
+    }
+
+    #[test]
+    fn the_store_keeps_strings_and_numbers() {
+        let path = std::env::temp_dir().join(format!("pile-test-{}.kv", std::process::id()));
+        let path = path.to_str().unwrap().replace('\\', "/");
+        let write = format!("\"{path}\" kvopen \"name\" \"a\\tb\\nc\" kvset \"count\" 42 kvset");
+        let p = parse(&write);
+        runtime(&p).run().unwrap();
+
+        let read = format!("\"{path}\" kvopen \"name\" kvget \"count\" kvget \"other\" kvget");
+        let p = parse(&read);
+        let mut r = runtime(&p);
+        r.run().unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(matches!(r.pop(), Some(Data::Number(n)) if n == -1.0));
+        assert!(matches!(r.pop(), Some(Data::Number(n)) if n == 42.0));
+        assert!(matches!(r.pop(), Some(Data::String(s)) if s == "a\tb\nc"));
+    }
+
+    #[test]
+    fn the_store_refuses_quotations() {
+        let path = std::env::temp_dir().join(format!("pile-quote-{}.kv", std::process::id()));
+        let path = path.to_str().unwrap().replace('\\', "/");
+        let source = format!("\"{path}\" kvopen \"q\" quote 1 end kvset");
+        let p = parse(&source);
+        let result = runtime(&p).run();
+        let _ = std::fs::remove_file(&path);
+        assert!(matches!(
+            result,
+            Err(RuntimeError::UnexpectedType(_, op, expected, got))
+                if op == "kvset" && expected == "string or number" && got == "quote"
+        ));
+    }
 }
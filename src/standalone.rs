@@ -0,0 +1,54 @@
+// Standalone executables made by `pile build`: a copy of the interpreter with the program
+// appended to it, followed by a trailer that lets the copy find the program again:
+//
+//     [interpreter][source][file name][source length: u64][name length: u64][MAGIC]
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+const MAGIC: &[u8; 8] = b"PILESRC\0";
+const TRAILER: u64 = 8 + 8 + 8;
+
+pub fn build(output: &str, filename: &str, source: &str) -> Result<(), String> {
+    let exe = std::env::current_exe().map_err(|e| e.to_string())?;
+    if embedded().is_some() {
+        return Err("a standalone executable can't build other programs.".to_string());
+    }
+    std::fs::copy(&exe, output).map_err(|e| format!("couldn't create {}: {}.", output, e))?;
+    let mut f = std::fs::OpenOptions::new()
+        .append(true)
+        .open(output)
+        .map_err(|e| e.to_string())?;
+    f.write_all(source.as_bytes())
+        .and_then(|_| f.write_all(filename.as_bytes()))
+        .and_then(|_| f.write_all(&(source.len() as u64).to_le_bytes()))
+        .and_then(|_| f.write_all(&(filename.len() as u64).to_le_bytes()))
+        .and_then(|_| f.write_all(MAGIC))
+        .map_err(|e| format!("couldn't write {}: {}.", output, e))
+}
+
+// The file name and source of the program appended to this executable, if any.
+pub fn embedded() -> Option<(String, String)> {
+    let mut f = File::open(std::env::current_exe().ok()?).ok()?;
+    let size = f.seek(SeekFrom::End(0)).ok()?;
+    if size < TRAILER {
+        return None;
+    }
+    let mut trailer = [0u8; TRAILER as usize];
+    f.seek(SeekFrom::End(-(TRAILER as i64))).ok()?;
+    f.read_exact(&mut trailer).ok()?;
+    if &trailer[16..] != MAGIC {
+        return None;
+    }
+    let source_len = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+    let name_len = u64::from_le_bytes(trailer[8..16].try_into().unwrap());
+    let start = size.checked_sub(TRAILER + source_len + name_len)?;
+    f.seek(SeekFrom::Start(start)).ok()?;
+    let mut source = vec![0u8; source_len as usize];
+    let mut name = vec![0u8; name_len as usize];
+    f.read_exact(&mut source).ok()?;
+    f.read_exact(&mut name).ok()?;
+    Some((
+        String::from_utf8(name).ok()?,
+        String::from_utf8(source).ok()?,
+    ))
+}
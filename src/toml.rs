@@ -0,0 +1,90 @@
+// The small subset of TOML used by pile.toml and the user configuration: [sections],
+// `key = value` lines with strings, numbers or booleans, and comments.
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Number(f64),
+    Bool(bool),
+}
+
+pub struct Entry {
+    pub section: String,
+    pub key: String,
+    pub value: Value,
+    pub line: usize,
+}
+
+pub fn read(path: &Path) -> Result<Vec<Entry>, String> {
+    let source = std::fs::read_to_string(path)
+        .map_err(|e| format!("couldn't read {}: {}.", path.display(), e))?;
+    let mut entries = Vec::new();
+    let mut section = String::new();
+
+    for (i, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |m: &str| format!("{}:{}: {}", path.display(), i + 1, m);
+        if let Some(s) = line.strip_prefix('[') {
+            section = s
+                .strip_suffix(']')
+                .ok_or_else(|| error("expected `]` after section name."))?
+                .trim()
+                .to_string();
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| error("expected `key = value`."))?;
+        let value = parse_value(value.trim())
+            .ok_or_else(|| error("expected a quoted string, a number, true or false."))?;
+        entries.push(Entry {
+            section: section.clone(),
+            key: key.trim().to_string(),
+            value,
+            line: i + 1,
+        });
+    }
+    Ok(entries)
+}
+
+fn parse_value(value: &str) -> Option<Value> {
+    if value.starts_with('"') {
+        return parse_string(value).map(Value::String);
+    }
+    let value = match value.split_once('#') {
+        Some((v, _)) => v.trim(),
+        None => value,
+    };
+    match value {
+        "true" => Some(Value::Bool(true)),
+        "false" => Some(Value::Bool(false)),
+        _ => value.parse().ok().map(Value::Number),
+    }
+}
+
+// Reads a basic TOML string ("..."), ignoring a trailing comment.
+fn parse_string(value: &str) -> Option<String> {
+    let rest = value.strip_prefix('"')?;
+    let mut s = String::new();
+    let mut chars = rest.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                let after = chars.as_str().trim();
+                return (after.is_empty() || after.starts_with('#')).then_some(s);
+            }
+            '\\' => match chars.next()? {
+                'n' => s.push('\n'),
+                't' => s.push('\t'),
+                c @ ('"' | '\\') => s.push(c),
+                _ => return None,
+            },
+            _ => s.push(c),
+        }
+    }
+    None
+}